@@ -1,8 +1,9 @@
+use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
 use crate::error::{PapyrusError, Result};
 use std::fs;
+use regex::{Captures, Regex};
 
-#[allow(dead_code)]
 pub fn copy_assets<P: AsRef<Path>>(source: P, destination: P) -> Result<()> {
     let source = source.as_ref();
     let destination = destination.as_ref();
@@ -43,3 +44,396 @@ pub fn ensure_export_directory<P: AsRef<Path>>(book_dir: P) -> Result<PathBuf> {
     fs::create_dir_all(&export_dir)?;
     Ok(export_dir)
 }
+
+
+/// Rewrites the local files referenced by `src`/`href`/`poster` attributes in
+/// `html` to where they go in the export, and adds them to `files` (path in
+/// the export to source file) for [`copy_files`].
+///
+/// A reference is resolved against `base_dir` (the chapter's directory) first,
+/// then against each of `roots`. The copy keeps the file's path relative to the
+/// first root containing it, so `content/images/a.png` and
+/// `assets/images/a.png` both end up in `<export>/images/a.png`. Missing files
+/// are reported and left untouched.
+pub fn collect_referenced_assets(html: &str, base_dir: &Path, roots: &[&Path], files: &mut BTreeMap<String, PathBuf>) -> Result<String> {
+    rewrite_references(html, &["src", "href", "poster"], |url| {
+        let (source, suffix) = match resolve_reference(url, base_dir, roots)? {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        
+        let destination = export_path(&source, roots);
+        files.insert(slash_path(&destination), source);
+        
+        Ok(Some(format!("{}{}", url_path(&destination), suffix)))
+    })
+}
+
+/// Copies `files` (path in the export to source file) into `export_dir`.
+/// Copies already in the export are only replaced with `refresh`.
+pub fn copy_files(files: &BTreeMap<String, PathBuf>, export_dir: &Path, refresh: bool) -> Result<()> {
+    for (path, source) in files {
+        let dest_path = export_dir.join(path);
+        if refresh || !dest_path.exists() {
+            if let Some(parent) = dest_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(source, &dest_path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Rewrites the local images, audio and video referenced by `src`/`poster`
+/// attributes in `html` to their place in an EPUB, `media/<path>`, and adds
+/// them to `files` (path in the book to source file).
+///
+/// References are resolved like [`collect_referenced_assets`], but a missing file
+/// is an error: e-readers have nowhere else to look. So is a file of a type
+/// [`media_type`] doesn't know, which readers would refuse to show.
+pub fn collect_referenced_media(html: &str, base_dir: &Path, roots: &[&Path], files: &mut BTreeMap<String, PathBuf>) -> Result<String> {
+    rewrite_references(html, &["src", "poster"], |url| {
+        let (source, suffix) = match find_reference(url, base_dir, roots)? {
+            Some(found) => found,
+            None => {
+                return Err(PapyrusError::Epub(format!(
+                    "referenced file not found: {} (from {})",
+                    percent_decode(url),
+                    base_dir.display()
+                )));
+            }
+        };
+        
+        if media_type(&source).is_none() {
+            return Err(PapyrusError::Epub(format!(
+                "unsupported media type: {} (from {}); use PNG, JPEG, GIF, WebP or SVG images, MP3, M4A or Ogg audio, or MP4 or WebM video",
+                percent_decode(url),
+                base_dir.display()
+            )));
+        }
+        
+        let destination = Path::new("media").join(export_path(&source, roots));
+        files.insert(slash_path(&destination), source);
+        
+        Ok(Some(format!("{}{}", url_path(&destination), suffix)))
+    })
+}
+
+/// Where a referenced file goes in the export: its path relative to the first
+/// of `roots` containing it, or `external/<name>`.
+fn export_path(source: &Path, roots: &[&Path]) -> PathBuf {
+    roots
+        .iter()
+        .filter_map(|root| root.canonicalize().ok())
+        .find_map(|root| source.strip_prefix(&root).ok().map(Path::to_path_buf))
+        .unwrap_or_else(|| PathBuf::from("external").join(source.file_name().unwrap_or_default()))
+}
+
+/// A relative path with `/` separators, as used in URLs and archives.
+pub fn slash_path(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// The files below `dir`, as sorted paths relative to it.
+pub fn relative_files(dir: &Path) -> Result<Vec<String>> {
+    let mut files = Vec::new();
+    for entry in walkdir::WalkDir::new(dir).sort_by_file_name() {
+        let entry = entry?;
+        if entry.file_type().is_file() {
+            files.push(slash_path(entry.path().strip_prefix(dir).unwrap_or(entry.path())));
+        }
+    }
+    Ok(files)
+}
+
+/// A relative path as a URL for an HTML attribute.
+pub fn url_path(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy().replace('&', "&amp;").replace('"', "&quot;").replace(' ', "%20"))
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Replaces the local files referenced by `src`/`poster` attributes in `html`
+/// with `data:` URIs, resolving them like [`collect_referenced_assets`].
+pub fn inline_referenced_assets(html: &str, base_dir: &Path, roots: &[&Path]) -> Result<String> {
+    rewrite_references(html, &["src", "poster"], |url| {
+        match resolve_reference(url, base_dir, roots)? {
+            Some((source, _)) => Ok(Some(data_uri(&source)?)),
+            None => Ok(None),
+        }
+    })
+}
+
+/// Replaces local `url(...)` references in a stylesheet, such as fonts and
+/// background images, with `data:` URIs. Paths are relative to `css_dir`.
+pub fn inline_css_urls(css: &str, css_dir: &Path) -> Result<String> {
+    let url_re = Regex::new(r#"url\(\s*(?:"([^"]*)"|'([^']*)'|([^)'"\s]*))\s*\)"#).unwrap();
+    let mut error = None;
+    
+    let inlined = url_re.replace_all(css, |caps: &Captures| {
+        let original = caps[0].to_string();
+        let url = caps.get(1).or_else(|| caps.get(2)).or_else(|| caps.get(3)).unwrap().as_str();
+        if error.is_some() || !is_local_reference(url) {
+            return original;
+        }
+        
+        match resolve_reference(url, css_dir, &[]).and_then(|found| found.map(|(source, _)| data_uri(&source)).transpose()) {
+            Ok(Some(uri)) => format!("url(\"{}\")", uri),
+            Ok(None) => original,
+            Err(e) => {
+                error = Some(e);
+                original
+            }
+        }
+    }).to_string();
+    
+    match error {
+        Some(e) => Err(e),
+        None => Ok(inlined),
+    }
+}
+
+/// Encodes a file as a `data:` URI.
+pub fn data_uri(path: &Path) -> Result<String> {
+    use base64::Engine;
+    let data = fs::read(path)?;
+    let media_type = media_type(path).unwrap_or("application/octet-stream");
+    Ok(format!("data:{};base64,{}", media_type, base64::engine::general_purpose::STANDARD.encode(data)))
+}
+
+/// The media type of common book assets, by file extension.
+pub fn media_type(path: &Path) -> Option<&'static str> {
+    let ext = path.extension()?.to_str()?.to_lowercase();
+    let media_type = match ext.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "css" => "text/css",
+        "js" => "text/javascript",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "mp3" => "audio/mpeg",
+        "m4a" => "audio/mp4",
+        "ogg" => "audio/ogg",
+        "mp4" | "m4v" => "video/mp4",
+        "webm" => "video/webm",
+        "pdf" => "application/pdf",
+        _ => return None,
+    };
+    Some(media_type)
+}
+
+/// Runs `replace` on every local URL found in the given attributes of the
+/// elements in `html`. `replace` returns the new URL, or `None` to keep the
+/// attribute as it is.
+///
+/// Only real tags are looked at: markup shown in code samples is escaped
+/// text (`&lt;img src='...'&gt;`) and stays untouched.
+fn rewrite_references<F>(html: &str, attributes: &[&str], mut replace: F) -> Result<String>
+where
+    F: FnMut(&str) -> Result<Option<String>>,
+{
+    let tag_re = Regex::new(r#"<[A-Za-z][^\s/>]*(?:[^>"']|"[^"]*"|'[^']*')*>"#).unwrap();
+    let attr_re = Regex::new(&format!(r#"(\s)({})=(?:"([^"]*)"|'([^']*)')"#, attributes.join("|"))).unwrap();
+    let mut error = None;
+    
+    let rewritten = tag_re.replace_all(html, |tag: &Captures| {
+        attr_re.replace_all(&tag[0], |caps: &Captures| {
+            let original = caps[0].to_string();
+            let url = caps.get(3).or_else(|| caps.get(4)).unwrap().as_str();
+            if error.is_some() || !is_local_reference(url) {
+                return original;
+            }
+            
+            match replace(url) {
+                Ok(Some(target)) => format!("{}{}=\"{}\"", &caps[1], &caps[2], target),
+                Ok(None) => original,
+                Err(e) => {
+                    error = Some(e);
+                    original
+                }
+            }
+        }).to_string()
+    }).to_string();
+    
+    match error {
+        Some(e) => Err(e),
+        None => Ok(rewritten),
+    }
+}
+
+/// Whether `url` points at a file next to the book rather than a web address,
+/// an in-page anchor or another chapter.
+fn is_local_reference(url: &str) -> bool {
+    let path = url.split(['#', '?']).next().unwrap_or_default();
+    !path.is_empty()
+        && !url.starts_with("//")
+        && !url.starts_with('/')
+        && !url.contains(':')
+        && !path.ends_with(".md")
+        && !path.ends_with(".html")
+}
+
+/// Finds the file a URL points to, looking in `base_dir` and then `roots`.
+/// Returns the canonical path and the `?query`/`#fragment` part of the URL.
+fn find_reference<'a>(url: &'a str, base_dir: &Path, roots: &[&Path]) -> Result<Option<(PathBuf, &'a str)>> {
+    let (path_part, suffix) = match url.find(['#', '?']) {
+        Some(index) => url.split_at(index),
+        None => (url, ""),
+    };
+    let relative = percent_decode(&path_part.replace("&amp;", "&"));
+    
+    let source = std::iter::once(base_dir)
+        .chain(roots.iter().copied())
+        .map(|dir| dir.join(&relative))
+        .find(|candidate| candidate.is_file());
+    
+    match source {
+        Some(source) => Ok(Some((source.canonicalize()?, suffix))),
+        None => Ok(None),
+    }
+}
+
+/// Like [`find_reference`], warning about missing files.
+fn resolve_reference<'a>(url: &'a str, base_dir: &Path, roots: &[&Path]) -> Result<Option<(PathBuf, &'a str)>> {
+    let found = find_reference(url, base_dir, roots)?;
+    if found.is_none() {
+        let path_part = url.split(['#', '?']).next().unwrap_or_default();
+        eprintln!("Warning: referenced file not found: {} (from {})", percent_decode(&path_part.replace("&amp;", "&")), base_dir.display());
+    }
+    Ok(found)
+}
+
+/// Decodes `%XX` escapes in a URL path.
+pub fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+            if let Some(byte) = hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory of its own for each test.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("papyrus-assets-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn copies_only_files_referenced_by_elements() {
+        let dir = scratch_dir("copy");
+        let content = dir.join("content");
+        let export = dir.join("export");
+        fs::create_dir_all(content.join("images")).unwrap();
+        fs::write(content.join("images").join("real.png"), b"png").unwrap();
+        fs::write(content.join("images").join("sample.png"), b"png").unwrap();
+
+        let html = concat!(
+            "<p><img src=\"images/real.png\" alt=\"\"></p>\n",
+            "<p><code>&lt;img src='images/sample.png'&gt;</code></p>\n",
+            "<pre><code>&lt;a href='images/sample.png'&gt;\n</code></pre>\n",
+        );
+        let mut files = BTreeMap::new();
+        let rewritten = collect_referenced_assets(html, &content, &[&content], &mut files).unwrap();
+        copy_files(&files, &export, true).unwrap();
+
+        assert_eq!(rewritten, html);
+        assert!(export.join("images").join("real.png").is_file());
+        assert!(!export.join("images").join("sample.png").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Chapter Markdown rendered the way `papyrus epub` does.
+    fn epub_html(markdown: &str) -> String {
+        let mut file = crate::markdown::MarkdownFile {
+            path: PathBuf::from("chapter.md"),
+            frontmatter: None,
+            content: markdown.to_string(),
+            html: String::new(),
+        };
+        file.render(crate::markdown::OutputFormat::Epub).unwrap();
+        crate::html::highlight_code_blocks(&file.html)
+    }
+
+    #[test]
+    fn epub_ignores_html_in_inline_code() {
+        let dir = scratch_dir("inline-code");
+        let html = epub_html("Use `<img src='logo.png'>` for the logo.\n");
+        let mut files = BTreeMap::new();
+
+        let rewritten = collect_referenced_media(&html, &dir, &[&dir], &mut files).unwrap();
+
+        assert_eq!(rewritten, html);
+        assert!(files.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn epub_ignores_html_in_code_blocks() {
+        let dir = scratch_dir("code-block");
+        let html = epub_html("```\n<img src='other.png'>\n```\n");
+        let mut files = BTreeMap::new();
+
+        let rewritten = collect_referenced_media(&html, &dir, &[&dir], &mut files).unwrap();
+
+        assert_eq!(rewritten, html);
+        assert!(files.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn epub_requires_files_of_real_images() {
+        let dir = scratch_dir("missing");
+        let html = epub_html("<img src='missing.png'>\n");
+        let mut files = BTreeMap::new();
+
+        assert!(collect_referenced_media(&html, &dir, &[&dir], &mut files).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn epub_rejects_media_readers_cannot_show() {
+        let dir = scratch_dir("avif");
+        fs::write(dir.join("photo.avif"), b"avif").unwrap();
+        fs::write(dir.join("photo.png"), b"png").unwrap();
+        let mut files = BTreeMap::new();
+
+        let error = collect_referenced_media("<img src=\"photo.avif\">", &dir, &[&dir], &mut files).unwrap_err();
+        assert!(error.to_string().contains("unsupported media type: photo.avif"), "{}", error);
+        assert!(collect_referenced_media("<img src=\"photo.png\">", &dir, &[&dir], &mut files).is_ok());
+        assert_eq!(files.keys().collect::<Vec<_>>(), ["media/photo.png"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rewrites_attributes_in_tags_only() {
+        let html = "<video poster='a.png' src=\"b.mp4\"></video> src='c.png'";
+        let rewritten = rewrite_references(html, &["src", "poster"], |url| Ok(Some(format!("x/{}", url)))).unwrap();
+        assert_eq!(rewritten, "<video poster=\"x/a.png\" src=\"x/b.mp4\"></video> src='c.png'");
+    }
+}
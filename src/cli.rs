@@ -1,6 +1,7 @@
 use clap::{Parser, Subcommand};
 use anyhow::Result;
 use std::path::PathBuf;
+use crate::watch;
 
 #[derive(Parser)]
 #[command(name = "papyrus")]
@@ -20,7 +21,7 @@ pub enum Commands {
     },
     /// Generate a PDF eBook
     Pdf {
-        /// Theme to use (light or dark)
+        /// Theme to use: light, dark, a built-in theme (see `theme list`) or a package in assets/themes/
         #[arg(default_value = "light")]
         theme: String,
         /// Content directory path
@@ -29,6 +30,9 @@ pub enum Commands {
         /// Book directory (where assets and config are located)
         #[arg(short, long)]
         book_dir: Option<PathBuf>,
+        /// Rebuild whenever the chapters, assets or configuration change
+        #[arg(short, long)]
+        watch: bool,
     },
     /// Generate an EPUB eBook
     Epub {
@@ -38,6 +42,12 @@ pub enum Commands {
         /// Book directory (where assets and config are located)
         #[arg(short, long)]
         book_dir: Option<PathBuf>,
+        /// Rebuild whenever the chapters, assets or configuration change
+        #[arg(short, long)]
+        watch: bool,
+        /// Validate the generated EPUB and report problems with file and line
+        #[arg(long, conflicts_with = "watch")]
+        check: bool,
     },
     /// Generate an HTML eBook
     Html {
@@ -47,13 +57,60 @@ pub enum Commands {
         /// Book directory (where assets and config are located)
         #[arg(short, long)]
         book_dir: Option<PathBuf>,
+        /// Write one page per chapter with navigation (same as `[html] multi_page = true`)
+        #[arg(long)]
+        multi_page: bool,
+        /// Write a single self-contained file with styles, fonts and images inlined
+        #[arg(long, conflicts_with = "multi_page")]
+        standalone: bool,
+        /// Rebuild whenever the chapters, assets or configuration change
+        #[arg(short, long)]
+        watch: bool,
+    },
+    /// Preview the HTML book in the browser, rebuilding and reloading on changes
+    Serve {
+        /// Content directory path
+        #[arg(short, long)]
+        content: Option<PathBuf>,
+        /// Book directory (where assets and config are located)
+        #[arg(short, long)]
+        book_dir: Option<PathBuf>,
+        /// Preview the multi-page website (same as `[html] multi_page = true`)
+        #[arg(long)]
+        multi_page: bool,
+        /// Port to listen on
+        #[arg(short, long, default_value_t = 3000)]
+        port: u16,
     },
     /// Generate a sample PDF
     Sample {
-        /// Theme to use (light or dark)
+        /// Theme to use: light, dark, a built-in theme (see `theme list`) or a package in assets/themes/
         #[arg(default_value = "light")]
         theme: String,
     },
+    /// List or customise themes
+    Theme {
+        #[command(subcommand)]
+        command: ThemeCommands,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ThemeCommands {
+    /// List the built-in themes and the book's theme packages
+    List {
+        /// Book directory (where assets and config are located)
+        #[arg(short, long)]
+        book_dir: Option<PathBuf>,
+    },
+    /// Copy a built-in theme into assets/themes/ for customisation
+    Eject {
+        /// Name of the built-in theme
+        name: String,
+        /// Book directory (where assets and config are located)
+        #[arg(short, long)]
+        book_dir: Option<PathBuf>,
+    },
 }
 
 pub async fn init_command(path: PathBuf) -> Result<()> {
@@ -63,36 +120,72 @@ pub async fn init_command(path: PathBuf) -> Result<()> {
     Ok(())
 }
 
-pub async fn pdf_command(theme: String, content: Option<PathBuf>, book_dir: Option<PathBuf>) -> Result<()> {
+pub async fn pdf_command(theme: String, content: Option<PathBuf>, book_dir: Option<PathBuf>, watch: bool) -> Result<()> {
     use crate::pdf;
     let book_dir = book_dir.unwrap_or_else(|| PathBuf::from("."));
     let content_dir = content.unwrap_or_else(|| book_dir.join("content"));
     
+    if watch {
+        return Ok(watch::rebuild_on_change(&book_dir, &content_dir, "PDF", || pdf::generate_pdf(&book_dir, &content_dir, &theme)).await?);
+    }
+    
     pdf::generate_pdf(&book_dir, &content_dir, &theme).await?;
     println!("PDF generated successfully!");
     Ok(())
 }
 
-pub async fn epub_command(content: Option<PathBuf>, book_dir: Option<PathBuf>) -> Result<()> {
-    use crate::epub;
+pub async fn epub_command(content: Option<PathBuf>, book_dir: Option<PathBuf>, watch: bool, check: bool) -> Result<()> {
+    use crate::{epub, epub_check};
     let book_dir = book_dir.unwrap_or_else(|| PathBuf::from("."));
     let content_dir = content.unwrap_or_else(|| book_dir.join("content"));
     
-    epub::generate_epub(&book_dir, &content_dir).await?;
+    if watch {
+        return Ok(watch::rebuild_on_change(&book_dir, &content_dir, "EPUB", || async {
+            epub::generate_epub(&book_dir, &content_dir).await.map(|_| ())
+        }).await?);
+    }
+    
+    let epub_path = epub::generate_epub(&book_dir, &content_dir).await?;
     println!("EPUB generated successfully!");
+    
+    if check {
+        let problems = epub_check::check(&epub_path)?;
+        if !problems.is_empty() {
+            for problem in &problems {
+                eprintln!("{}", problem);
+            }
+            anyhow::bail!("{} found {} problem(s)", epub_path.display(), problems.len());
+        }
+        println!("No problems found in {}", epub_path.display());
+    }
     Ok(())
 }
 
-pub async fn html_command(content: Option<PathBuf>, book_dir: Option<PathBuf>) -> Result<()> {
+pub async fn html_command(content: Option<PathBuf>, book_dir: Option<PathBuf>, multi_page: bool, standalone: bool, watch: bool) -> Result<()> {
     use crate::html;
     let book_dir = book_dir.unwrap_or_else(|| PathBuf::from("."));
     let content_dir = content.unwrap_or_else(|| book_dir.join("content"));
     
-    html::generate_html(&book_dir, &content_dir).await?;
+    if watch {
+        return Ok(watch::rebuild_on_change(&book_dir, &content_dir, "HTML", || async {
+            html::generate_html(&book_dir, &content_dir, multi_page, standalone).await.map(|_| ())
+        }).await?);
+    }
+    
+    html::generate_html(&book_dir, &content_dir, multi_page, standalone).await?;
     println!("HTML generated successfully!");
     Ok(())
 }
 
+pub async fn serve_command(content: Option<PathBuf>, book_dir: Option<PathBuf>, multi_page: bool, port: u16) -> Result<()> {
+    use crate::serve;
+    let book_dir = book_dir.unwrap_or_else(|| PathBuf::from("."));
+    let content_dir = content.unwrap_or_else(|| book_dir.join("content"));
+    
+    serve::serve(&book_dir, &content_dir, multi_page, port).await?;
+    Ok(())
+}
+
 pub async fn sample_command(theme: String) -> Result<()> {
     use crate::pdf;
     let book_dir = PathBuf::from(".");
@@ -103,3 +196,50 @@ pub async fn sample_command(theme: String) -> Result<()> {
     println!("Sample PDF generated successfully!");
     Ok(())
 }
+
+pub async fn theme_list_command(book_dir: Option<PathBuf>) -> Result<()> {
+    use crate::theme::BUILTIN_THEMES;
+    let book_dir = book_dir.unwrap_or_else(|| PathBuf::from("."));
+    
+    println!("Built-in themes:");
+    for theme in BUILTIN_THEMES {
+        println!("  {:<10} {}", theme.name, theme.description);
+    }
+    
+    let themes_dir = book_dir.join("assets").join("themes");
+    if let Ok(entries) = std::fs::read_dir(&themes_dir) {
+        let mut names: Vec<String> = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_dir())
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        
+        if !names.is_empty() {
+            println!("\nTheme packages in {}:", themes_dir.display());
+            for name in names {
+                println!("  {}", name);
+            }
+        }
+    }
+    
+    println!("\nSelect one in papyrus.toml:\n\n[theme]\nname = \"classic\"");
+    Ok(())
+}
+
+pub async fn theme_eject_command(name: String, book_dir: Option<PathBuf>) -> Result<()> {
+    use crate::theme::{BuiltinTheme, BUILTIN_THEMES};
+    let book_dir = book_dir.unwrap_or_else(|| PathBuf::from("."));
+    
+    let theme = BuiltinTheme::find(&name).ok_or_else(|| anyhow::anyhow!(
+        "Unknown theme: {} (built-in themes: {})",
+        name,
+        BUILTIN_THEMES.iter().map(|theme| theme.name).collect::<Vec<_>>().join(", ")
+    ))?;
+    
+    let theme_dir = book_dir.join("assets").join("themes").join(&name);
+    theme.eject(&theme_dir)?;
+    println!("Copied theme \"{}\" to {}", name, theme_dir.display());
+    println!("Select it with `[theme] name = \"{}\"` in papyrus.toml; the copy takes precedence over the built-in theme.", name);
+    Ok(())
+}
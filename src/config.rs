@@ -1,13 +1,14 @@
 use serde::{Deserialize, Serialize};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use crate::error::{PapyrusError, Result};
 use std::fs;
-use toml;
 
 // Embed template files at compile time
 const THEME_LIGHT_TEMPLATE: &str = include_str!("../templates/stubs/assets/theme-light.html");
 const THEME_DARK_TEMPLATE: &str = include_str!("../templates/stubs/assets/theme-dark.html");
 const THEME_HTML_TEMPLATE: &str = include_str!("../templates/stubs/assets/theme-html.html");
+pub const THEME_HTML_PAGE_TEMPLATE: &str = include_str!("../templates/stubs/assets/theme-html-page.html");
+pub const THEME_HTML_INDEX_TEMPLATE: &str = include_str!("../templates/stubs/assets/theme-html-index.html");
 const STYLE_CSS_TEMPLATE: &str = include_str!("../templates/stubs/assets/style.css");
 const COVER_IMAGE: &[u8] = include_bytes!("../templates/stubs/assets/images/cover.png");
 
@@ -25,12 +26,23 @@ const SAMPLE_CHAPTER_08: &str = include_str!("../templates/stubs/content/08-cove
 pub struct Config {
     pub title: String,
     pub author: String,
+    /// One or two sentences about the book, for search engines and link previews
+    pub description: Option<String>,
     pub language: Option<String>,
     pub cover: Option<String>,
     pub version: Option<String>,
+    /// Unique identifier of the book (UUID, ISBN or URI), used by EPUB readers
+    /// to recognise it across rebuilds
+    pub identifier: Option<String>,
     pub md_file_list: Option<Vec<String>>,
     pub sample: Option<SampleConfig>,
     pub fonts: Option<Vec<FontConfig>>,
+    /// Custom values available to chapters as `{{ name }}`
+    pub variables: Option<toml::Table>,
+    pub preprocessors: Option<Vec<PreprocessorConfig>>,
+    pub html: Option<HtmlConfig>,
+    pub theme: Option<ThemeConfig>,
+    pub metadata: Option<MetadataConfig>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -39,6 +51,107 @@ pub struct SampleConfig {
     pub end_page: Option<u32>,
 }
 
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HtmlConfig {
+    /// Write one page per chapter into `export/html/` instead of a single file
+    pub multi_page: Option<bool>,
+    /// Generate a search index and enable the theme's search box (default: true)
+    pub search: Option<bool>,
+    /// Public address of the book, for canonical links, social cards and `sitemap.xml`
+    pub site_url: Option<String>,
+    /// Write into `export/html/<version>/` and list the editions in `versions.json`
+    pub versioned: Option<bool>,
+    /// Make the multi-page book an installable web app that works offline
+    pub pwa: Option<bool>,
+}
+
+/// Publishing details for EPUB, PDF and HTML metadata.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MetadataConfig {
+    /// Summary of the book; takes the place of the top-level `description`
+    pub description: Option<String>,
+    /// Everyone who wrote the book, in order; replaces `author` in book metadata
+    pub authors: Option<Vec<PersonConfig>>,
+    /// Editors, illustrators, translators and others, each with a `role`
+    pub contributors: Option<Vec<PersonConfig>>,
+    pub publisher: Option<String>,
+    pub subjects: Option<Vec<String>>,
+    pub rights: Option<String>,
+    /// Publication date: `YYYY`, `YYYY-MM` or `YYYY-MM-DD`
+    pub date: Option<String>,
+    pub isbn: Option<String>,
+    pub series: Option<SeriesConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PersonConfig {
+    pub name: String,
+    /// Name for sorting, e.g. `Doe, Jane`
+    pub file_as: Option<String>,
+    /// editor, illustrator, translator, ... or a MARC relator code such as `edt`
+    pub role: Option<String>,
+}
+
+impl PersonConfig {
+    /// The MARC relator code of the person's role. Authors without a role are
+    /// `aut`, contributors `ctb`.
+    pub fn role_code(&self, default: &'static str) -> Option<&str> {
+        let role = match self.role.as_deref().map(str::trim) {
+            Some(role) if !role.is_empty() => role,
+            _ => return Some(default),
+        };
+        let code = ROLES.iter()
+            .find(|(name, code)| role.eq_ignore_ascii_case(name) || role == *code)
+            .map(|(_, code)| *code);
+        code.or_else(|| (role.len() == 3 && role.chars().all(|c| c.is_ascii_lowercase())).then_some(role))
+    }
+}
+
+/// Common contributor roles and their MARC relator codes.
+const ROLES: &[(&str, &str)] = &[
+    ("author", "aut"),
+    ("editor", "edt"),
+    ("illustrator", "ill"),
+    ("translator", "trl"),
+    ("narrator", "nrt"),
+    ("photographer", "pht"),
+    ("designer", "dsr"),
+    ("contributor", "ctb"),
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SeriesConfig {
+    pub name: String,
+    /// The book's number in the series
+    pub position: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThemeConfig {
+    /// Theme package in `assets/themes/<name>/`
+    pub name: String,
+    /// Theme package directory anywhere else, relative to the book (overrides `name`)
+    pub path: Option<String>,
+}
+
+impl ThemeConfig {
+    /// The directory holding the theme package.
+    pub fn directory(&self, book_dir: &Path) -> PathBuf {
+        match &self.path {
+            Some(path) => book_dir.join(path),
+            None => book_dir.join("assets").join("themes").join(&self.name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PreprocessorConfig {
+    /// Shell command that reads the book as JSON on stdin and writes it back
+    pub command: String,
+    /// Outputs to run for (html, epub, pdf); all of them when omitted
+    pub formats: Option<Vec<String>>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FontConfig {
     pub name: String,
@@ -59,10 +172,66 @@ impl Config {
         let content = fs::read_to_string(&config_path)?;
         let config: Config = toml::from_str(&content)
             .map_err(|e| PapyrusError::Config(format!("Failed to parse config: {}", e)))?;
+        config.validate_metadata()?;
         
         Ok(config)
     }
     
+    /// The book's authors, from `[metadata] authors` or `author`.
+    pub fn authors(&self) -> Vec<PersonConfig> {
+        match self.metadata.as_ref().and_then(|metadata| metadata.authors.as_ref()) {
+            Some(authors) if !authors.is_empty() => authors.clone(),
+            _ => vec![PersonConfig { name: self.author.clone(), file_as: None, role: None }],
+        }
+    }
+    
+    /// `[metadata] description`, or the top-level `description`.
+    pub fn description(&self) -> Option<&str> {
+        self.metadata
+            .as_ref()
+            .and_then(|metadata| metadata.description.as_deref())
+            .or(self.description.as_deref())
+    }
+    
+    /// The ISBN as `urn:isbn:<digits>`, when `[metadata] isbn` is set.
+    pub fn isbn(&self) -> Option<String> {
+        self.metadata.as_ref()?.isbn.as_deref().and_then(isbn_urn)
+    }
+    
+    fn validate_metadata(&self) -> Result<()> {
+        let metadata = match &self.metadata {
+            Some(metadata) => metadata,
+            None => return Ok(()),
+        };
+        
+        let people = metadata.authors.iter().flatten().map(|person| (person, "aut"))
+            .chain(metadata.contributors.iter().flatten().map(|person| (person, "ctb")));
+        for (person, default) in people {
+            if person.role_code(default).is_none() {
+                return Err(PapyrusError::Config(format!(
+                    "Unknown role for {}: {} (use editor, illustrator, translator, ... or a MARC relator code)",
+                    person.name,
+                    person.role.as_deref().unwrap_or_default()
+                )));
+            }
+        }
+        if let Some(isbn) = &metadata.isbn
+            && isbn_urn(isbn).is_none()
+        {
+            return Err(PapyrusError::Config(format!("Not an ISBN: {}", isbn)));
+        }
+        if let Some(date) = &metadata.date {
+            let valid = regex::Regex::new(r"^\d{4}(-\d{2}(-\d{2})?)?$").unwrap().is_match(date);
+            if !valid {
+                return Err(PapyrusError::Config(format!(
+                    "Publication date must be YYYY, YYYY-MM or YYYY-MM-DD: {}",
+                    date
+                )));
+            }
+        }
+        Ok(())
+    }
+    
     pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
         let config_path = path.as_ref().join("papyrus.toml");
         let toml = toml::to_string_pretty(self)
@@ -75,12 +244,19 @@ impl Config {
         Config {
             title: "My Book".to_string(),
             author: "Author Name".to_string(),
+            description: None,
             language: Some("en".to_string()),
             cover: Some("cover.png".to_string()),
             version: Some("1.0.0".to_string()),
+            identifier: None,
             md_file_list: None,
             sample: None,
             fonts: None,
+            variables: None,
+            preprocessors: None,
+            html: None,
+            theme: None,
+            metadata: None,
         }
     }
 }
@@ -94,8 +270,11 @@ pub async fn init_project<P: AsRef<Path>>(path: P) -> Result<()> {
     fs::create_dir_all(path.join("assets").join("images"))?;
     fs::create_dir_all(path.join("export"))?;
     
-    // Create default config
-    let config = Config::default();
+    // Create default config, with an identifier of its own for the book
+    let config = Config {
+        identifier: Some(format!("urn:uuid:{}", uuid::Uuid::new_v4())),
+        ..Config::default()
+    };
     config.save(path)?;
     
     // Create sample content files from templates
@@ -112,6 +291,8 @@ pub async fn init_project<P: AsRef<Path>>(path: P) -> Result<()> {
     copy_template_file(path, "theme-light.html", THEME_LIGHT_TEMPLATE)?;
     copy_template_file(path, "theme-dark.html", THEME_DARK_TEMPLATE)?;
     copy_template_file(path, "theme-html.html", THEME_HTML_TEMPLATE)?;
+    copy_template_file(path, "theme-html-page.html", THEME_HTML_PAGE_TEMPLATE)?;
+    copy_template_file(path, "theme-html-index.html", THEME_HTML_INDEX_TEMPLATE)?;
     copy_template_file(path, "style.css", STYLE_CSS_TEMPLATE)?;
     
     // Copy default cover image
@@ -121,8 +302,95 @@ pub async fn init_project<P: AsRef<Path>>(path: P) -> Result<()> {
     Ok(())
 }
 
+/// `value` as `urn:isbn:<digits>`, if it is an ISBN-10 or ISBN-13 with a
+/// valid check digit, written with or without hyphens and spaces and a
+/// prefix such as `ISBN`, `ISBN-13:` or `urn:isbn:`.
+pub fn isbn_urn(value: &str) -> Option<String> {
+    let value = value.trim();
+    let lower = value.to_ascii_lowercase();
+    let rest = ["urn:isbn:", "isbn-13", "isbn-10", "isbn13", "isbn10", "isbn"]
+        .iter()
+        .find(|prefix| lower.starts_with(*prefix))
+        .map_or(value, |prefix| &value[prefix.len()..]);
+    let isbn: String = rest
+        .trim_start_matches([':', ' '])
+        .chars()
+        .filter(|c| !matches!(c, '-' | ' '))
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    
+    let digits: Option<Vec<u32>> = isbn
+        .chars()
+        .enumerate()
+        .map(|(i, c)| match c {
+            'X' if i == 9 && isbn.len() == 10 => Some(10),
+            _ => c.to_digit(10),
+        })
+        .collect();
+    let valid = match (digits?.as_slice(), isbn.len()) {
+        (digits, 10) => digits.iter().zip((1..=10).rev()).map(|(d, w)| d * w).sum::<u32>() % 11 == 0,
+        (digits, 13) => digits.iter().zip([1, 3].iter().cycle()).map(|(d, w)| d * w).sum::<u32>() % 10 == 0,
+        _ => false,
+    };
+    valid.then(|| format!("urn:isbn:{}", isbn))
+}
+
 fn copy_template_file<P: AsRef<Path>>(path: P, filename: &str, content: &str) -> Result<()> {
     let assets_dir = path.as_ref().join("assets");
     fs::write(assets_dir.join(filename), content)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognises_isbn_13() {
+        assert_eq!(isbn_urn("978-3-16-148410-0").as_deref(), Some("urn:isbn:9783161484100"));
+        assert_eq!(isbn_urn("ISBN 978 3 16 148410 0").as_deref(), Some("urn:isbn:9783161484100"));
+        assert_eq!(isbn_urn("urn:isbn:9783161484100").as_deref(), Some("urn:isbn:9783161484100"));
+    }
+
+    #[test]
+    fn recognises_isbn_10_with_check_digit_x() {
+        assert_eq!(isbn_urn("0-8044-2957-X").as_deref(), Some("urn:isbn:080442957X"));
+        assert_eq!(isbn_urn("isbn:080442957X").as_deref(), Some("urn:isbn:080442957X"));
+    }
+
+    #[test]
+    fn accepts_common_isbn_prefixes() {
+        for value in ["ISBN: 978-3-16-148410-0", "ISBN-13 978-3-16-148410-0", "isbn13:9783161484100", "URN:ISBN:9783161484100"] {
+            assert_eq!(isbn_urn(value).as_deref(), Some("urn:isbn:9783161484100"), "{}", value);
+        }
+        assert_eq!(isbn_urn("ISBN-10: 0-8044-2957-x").as_deref(), Some("urn:isbn:080442957X"));
+    }
+
+    #[test]
+    fn checks_the_check_digit() {
+        assert_eq!(isbn_urn("978-3-16-148410-1"), None);
+        assert_eq!(isbn_urn("0-8044-2957-9"), None);
+        assert_eq!(isbn_urn("0-306-40615-2").as_deref(), Some("urn:isbn:0306406152"));
+    }
+
+    #[test]
+    fn rejects_what_is_not_an_isbn() {
+        assert_eq!(isbn_urn("12345"), None);
+        assert_eq!(isbn_urn("08044X2957"), None);
+        assert_eq!(isbn_urn("978316148410X"), None);
+        assert_eq!(isbn_urn("urn:uuid:85aa06c9-05fa-5ebe-8c9c-f53b530b80e2"), None);
+    }
+
+    #[test]
+    fn maps_contributor_roles_to_relator_codes() {
+        let person = |role: Option<&str>| PersonConfig {
+            name: "Jane Doe".to_string(),
+            file_as: None,
+            role: role.map(str::to_string),
+        };
+        assert_eq!(person(Some("Translator")).role_code("ctb"), Some("trl"));
+        assert_eq!(person(Some("edt")).role_code("ctb"), Some("edt"));
+        assert_eq!(person(None).role_code("aut"), Some("aut"));
+        assert_eq!(person(Some("ghostwriter")).role_code("ctb"), None);
+    }
+}
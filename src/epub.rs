@@ -1,15 +1,21 @@
-use std::path::Path;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 use std::fs::{File, create_dir_all};
 use std::io::Write;
 use zip::{ZipWriter, CompressionMethod};
 use zip::write::FileOptions;
-use crate::error::Result;
-use crate::markdown::MarkdownFile;
-use crate::config::Config;
-use crate::html::highlight_code_blocks;
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use uuid::Uuid;
+use crate::assets;
+use crate::error::{PapyrusError, Result};
+use crate::markdown::{MarkdownFile, OutputFormat, chapter_title};
+use crate::config::{Config, PersonConfig, isbn_urn};
+use crate::html::{highlight_code_blocks, highlight_css};
+use crate::theme::Theme;
 use std::fs;
 
-pub async fn generate_epub<P: AsRef<Path>>(book_dir: P, content_dir: P) -> Result<()> {
+/// Writes the EPUB and returns its path.
+pub async fn generate_epub<P: AsRef<Path>>(book_dir: P, content_dir: P) -> Result<PathBuf> {
     let book_dir = book_dir.as_ref();
     let content_dir = content_dir.as_ref();
     
@@ -18,46 +24,82 @@ pub async fn generate_epub<P: AsRef<Path>>(book_dir: P, content_dir: P) -> Resul
     
     // Collect and parse markdown files
     let md_files = crate::markdown::collect_markdown_files(content_dir, config.md_file_list.as_deref())?;
-    let parsed_files = crate::markdown::parse_markdown_files(&md_files)?;
+    let parsed_files = crate::markdown::parse_markdown_files(book_dir, &md_files, &config, OutputFormat::Epub)?;
     
-    // Process markdown with syntax highlighting
+    // Process markdown with syntax highlighting, and gather the images, audio
+    // and video chapters use so they can travel inside the book
+    let assets_dir = book_dir.join("assets");
+    let roots = [content_dir, &assets_dir, book_dir];
+    let mut media = BTreeMap::new();
     let processed_files: Vec<MarkdownFile> = parsed_files
         .into_iter()
         .map(|mut file| {
+            let chapter_dir = file.path.parent().unwrap_or(content_dir).to_path_buf();
             file.html = highlight_code_blocks(&file.html);
-            file
+            file.html = assets::collect_referenced_media(&file.html, &chapter_dir, &roots, &mut media)?;
+            Ok(file)
         })
-        .collect();
+        .collect::<Result<_>>()?;
     
     // Create EPUB structure
     let export_dir = book_dir.join("export");
     create_dir_all(&export_dir)?;
     
     let epub_path = export_dir.join(format!("{}.epub", sanitize_filename(&config.title)));
-    let file = File::create(&epub_path)?;
-    let mut zip = ZipWriter::new(file);
+    // Write next to the previous book and swap it in once complete, so a
+    // failed build leaves the previous book intact
+    let temp_path = export_dir.join("temp_epub.epub");
+    let cover = find_cover(book_dir, &config);
+    if let Err(e) = write_epub(&temp_path, book_dir, &config, &processed_files, &media, cover.as_ref()) {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e);
+    }
+    fs::rename(&temp_path, &epub_path)?;
     
-    let options = FileOptions::default()
-        .compression_method(CompressionMethod::Deflated)
-        .unix_permissions(0o644);
+    Ok(epub_path)
+}
+
+fn write_epub(
+    path: &Path,
+    book_dir: &Path,
+    config: &Config,
+    processed_files: &[MarkdownFile],
+    media: &BTreeMap<String, PathBuf>,
+    cover: Option<&Cover>,
+) -> Result<()> {
+    let modified = build_time(config, processed_files)?;
+    let mut entries: BTreeMap<String, Vec<u8>> = BTreeMap::new();
     
-    // Write mimetype (must be first, uncompressed)
-    zip.start_file("mimetype", FileOptions::default().compression_method(CompressionMethod::Stored))?;
-    zip.write_all(b"application/epub+zip")?;
+    // A theme stylesheet can link the package's static files, which then
+    // travel inside the book next to the chapter media
+    let theme = Theme::load(book_dir, config)?;
+    let mut media = media.clone();
+    if theme.has_template("epub.css")
+        && let Some(static_dir) = theme.static_dir()
+    {
+        for file in assets::relative_files(&static_dir)? {
+            let source = static_dir.join(&file);
+            if assets::media_type(&source).is_some() {
+                media.insert(format!("static/{}", file), source);
+            } else {
+                eprintln!("Warning: theme file {} has no EPUB media type, leaving it out", source.display());
+            }
+        }
+    }
     
     // Create META-INF directory
-    zip.start_file("META-INF/container.xml", options)?;
-    zip.write_all(include_bytes!("../templates/epub_container.xml"))?;
+    entries.insert("META-INF/container.xml".to_string(), include_bytes!("../templates/epub_container.xml").to_vec());
     
     // Create OPF file
-    let opf_content = generate_opf(&config, &processed_files)?;
-    zip.start_file("OEBPS/content.opf", options)?;
-    zip.write_all(opf_content.as_bytes())?;
+    let opf_content = generate_opf(config, processed_files, &media, cover, modified)?;
+    entries.insert("OEBPS/content.opf".to_string(), opf_content.into_bytes());
     
     // Create NCX file (table of contents)
-    let ncx_content = generate_ncx(&config, &processed_files)?;
-    zip.start_file("OEBPS/toc.ncx", options)?;
-    zip.write_all(ncx_content.as_bytes())?;
+    let ncx_content = generate_ncx(config, processed_files)?;
+    entries.insert("OEBPS/toc.ncx".to_string(), ncx_content.into_bytes());
+    
+    // EPUB 3 navigation document; the NCX stays for EPUB 2 readers
+    entries.insert("OEBPS/nav.xhtml".to_string(), generate_nav(config, processed_files, cover).into_bytes());
     
     // Write HTML files
     for (index, file) in processed_files.iter().enumerate() {
@@ -80,40 +122,60 @@ pub async fn generate_epub<P: AsRef<Path>>(book_dir: P, content_dir: P) -> Resul
             file.html
         );
         
-        let filename = format!("OEBPS/chapter{:03}.xhtml", index + 1);
-        zip.start_file(&filename, options)?;
-        zip.write_all(html_content.as_bytes())?;
+        entries.insert(format!("OEBPS/chapter{:03}.xhtml", index + 1), html_content.into_bytes());
     }
     
-    // Copy CSS file
+    for (media_path, source) in &media {
+        entries.insert(format!("OEBPS/{}", media_path), fs::read(source)?);
+    }
+    
+    // Copy CSS file, or use the theme's own stylesheet
     let css_path = book_dir.join("assets").join("style.css");
-    if css_path.exists() {
-        let css_content = fs::read_to_string(&css_path)?;
-        zip.start_file("OEBPS/style.css", options)?;
-        zip.write_all(css_content.as_bytes())?;
-    }
-    
-    // Copy cover image if exists (only image formats supported)
-    if let Some(cover_path) = &config.cover {
-        let cover_file_path = book_dir.join("assets").join("images").join(cover_path);
-        if cover_file_path.exists() {
-            let ext = cover_file_path.extension()
-                .and_then(|s| s.to_str())
-                .map(|s| s.to_lowercase())
-                .unwrap_or_else(|| "jpg".to_string());
-            
-            // EPUB supports PNG, JPG, GIF, SVG, WEBP - only image formats
-            match ext.as_str() {
-                "png" | "jpg" | "jpeg" | "gif" | "webp" | "svg" => {
-                    let cover_data = fs::read(&cover_file_path)?;
-                    zip.start_file(format!("OEBPS/cover.{}", ext), options)?;
-                    zip.write_all(&cover_data)?;
-                }
-                _ => {
-                    // Skip unsupported formats (like PDF)
-                }
-            }
-        }
+    let css_content = if theme.has_template("epub.css") {
+        let mut context = tera::Context::new();
+        context.insert("title", &config.title);
+        context.insert("book", config);
+        context.insert("static_url", "static/");
+        theme.render("epub.css", &context)?
+    } else if css_path.exists() {
+        fs::read_to_string(&css_path)?
+    } else {
+        String::new()
+    };
+    // Colours for highlighted code
+    entries.insert(
+        "OEBPS/style.css".to_string(),
+        format!("{}\n{}\n", css_content, highlight_css("light", "")).into_bytes(),
+    );
+    
+    // Cover image, and a page showing it at the start of the book
+    if let Some(cover) = cover {
+        entries.insert(format!("OEBPS/{}", cover.file_name), fs::read(&cover.source)?);
+        entries.insert("OEBPS/cover.xhtml".to_string(), generate_cover_page(config, cover).into_bytes());
+    }
+    
+    // Same sources, same bytes: entries in name order, all stamped with the
+    // build time instead of the moment they were written
+    let timestamp = zip_timestamp(modified);
+    let mut zip = ZipWriter::new(File::create(path)?);
+    
+    // Write mimetype (must be first, uncompressed)
+    zip.start_file(
+        "mimetype",
+        FileOptions::default()
+            .compression_method(CompressionMethod::Stored)
+            .last_modified_time(timestamp)
+            .unix_permissions(0o644),
+    )?;
+    zip.write_all(b"application/epub+zip")?;
+    
+    let options = FileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .last_modified_time(timestamp)
+        .unix_permissions(0o644);
+    for (name, content) in &entries {
+        zip.start_file(name, options)?;
+        zip.write_all(content)?;
     }
     
     zip.finish()?;
@@ -121,27 +183,233 @@ pub async fn generate_epub<P: AsRef<Path>>(book_dir: P, content_dir: P) -> Resul
     Ok(())
 }
 
-fn generate_opf(config: &Config, files: &[MarkdownFile]) -> Result<String> {
+/// The date the book is stamped with, which only depends on its contents:
+/// `SOURCE_DATE_EPOCH` when set, as for other reproducible builds, otherwise
+/// `[metadata] date`, the newest chapter `date`, or 1980-01-01.
+fn build_time(config: &Config, files: &[MarkdownFile]) -> Result<DateTime<Utc>> {
+    if let Ok(epoch) = std::env::var("SOURCE_DATE_EPOCH") {
+        return epoch
+            .trim()
+            .parse::<i64>()
+            .ok()
+            .and_then(|seconds| DateTime::from_timestamp(seconds, 0))
+            .ok_or_else(|| PapyrusError::Config(format!("SOURCE_DATE_EPOCH is not a Unix timestamp: {}", epoch)));
+    }
+    
+    let publication = config.metadata.as_ref().and_then(|metadata| metadata.date.as_deref()).and_then(parse_date);
+    let newest_chapter = files
+        .iter()
+        .filter_map(|file| file.frontmatter.as_ref()?.date.as_deref())
+        .filter_map(parse_date)
+        .max();
+    Ok(publication.or(newest_chapter).unwrap_or_else(|| parse_date("1980").unwrap_or_default()))
+}
+
+/// Midnight UTC of a `YYYY`, `YYYY-MM` or `YYYY-MM-DD` date, ignoring any
+/// time after the date.
+fn parse_date(date: &str) -> Option<DateTime<Utc>> {
+    let date = date.trim();
+    let day = match date.len() {
+        4 => format!("{}-01-01", date),
+        7 => format!("{}-01", date),
+        _ => date.get(..10)?.to_string(),
+    };
+    let day = chrono::NaiveDate::parse_from_str(&day, "%Y-%m-%d").ok()?;
+    Some(day.and_hms_opt(0, 0, 0)?.and_utc())
+}
+
+/// `time` as a ZIP timestamp, which can't go before 1980.
+fn zip_timestamp(time: DateTime<Utc>) -> zip::DateTime {
+    zip::DateTime::from_date_and_time(
+        time.year().clamp(1980, 2107) as u16,
+        time.month() as u8,
+        time.day() as u8,
+        time.hour() as u8,
+        time.minute() as u8,
+        time.second() as u8,
+    )
+    .unwrap_or_default()
+}
+
+/// The book's unique identifier as a URI: `identifier` from the
+/// configuration, the ISBN, or a UUID derived from the title and author, so
+/// readers recognise every rebuild as the same book.
+fn book_identifier(config: &Config) -> String {
+    let identifier = config.identifier.as_deref().map(str::trim).filter(|id| !id.is_empty());
+    let identifier = match identifier {
+        Some(identifier) => identifier,
+        None => {
+            return config.isbn().unwrap_or_else(|| {
+                let name = format!("papyrus:{}:{}", config.title, config.author);
+                format!("urn:uuid:{}", Uuid::new_v5(&Uuid::NAMESPACE_URL, name.as_bytes()))
+            });
+        }
+    };
+    
+    // Also accepts urn:uuid:...
+    if let Ok(uuid) = Uuid::parse_str(identifier) {
+        return format!("urn:uuid:{}", uuid);
+    }
+    
+    if let Some(isbn) = isbn_urn(identifier) {
+        return isbn;
+    }
+    
+    identifier.to_string()
+}
+
+/// The OPF metadata from `author` and `[metadata]`: creators and contributors
+/// with their roles and sort names, publishing details and the series.
+fn publication_metadata(config: &Config) -> String {
+    let mut metadata = String::new();
+    for (index, author) in config.authors().iter().enumerate() {
+        metadata.push_str(&person_metadata("creator", &format!("creator{}", index + 1), author, "aut"));
+    }
+    if let Some(description) = config.description() {
+        metadata.push_str(&format!("        <dc:description>{}</dc:description>\n", xml_escape(description)));
+    }
+    let publishing = match &config.metadata {
+        Some(publishing) => publishing,
+        None => return metadata,
+    };
+    for (index, contributor) in publishing.contributors.iter().flatten().enumerate() {
+        metadata.push_str(&person_metadata("contributor", &format!("contributor{}", index + 1), contributor, "ctb"));
+    }
+    
+    let mut element = |name: &str, value: &str| {
+        metadata.push_str(&format!("        <dc:{0}>{1}</dc:{0}>\n", name, xml_escape(value)));
+    };
+    if let Some(publisher) = &publishing.publisher {
+        element("publisher", publisher);
+    }
+    for subject in publishing.subjects.iter().flatten() {
+        element("subject", subject);
+    }
+    if let Some(rights) = &publishing.rights {
+        element("rights", rights);
+    }
+    if let Some(date) = &publishing.date {
+        element("date", date);
+    }
+    
+    if let Some(series) = &publishing.series {
+        metadata.push_str(&format!(
+            "        <meta property=\"belongs-to-collection\" id=\"series\">{}</meta>\n        <meta refines=\"#series\" property=\"collection-type\">series</meta>\n",
+            xml_escape(&series.name)
+        ));
+        if let Some(position) = series.position {
+            metadata.push_str(&format!("        <meta refines=\"#series\" property=\"group-position\">{}</meta>\n", position));
+        }
+    }
+    metadata
+}
+
+/// A `dc:creator` or `dc:contributor` with its role and sort name.
+fn person_metadata(element: &str, id: &str, person: &PersonConfig, default: &'static str) -> String {
+    let mut metadata = format!("        <dc:{0} id=\"{1}\">{2}</dc:{0}>\n", element, id, xml_escape(&person.name));
+    metadata.push_str(&format!(
+        "        <meta refines=\"#{}\" property=\"role\" scheme=\"marc:relators\">{}</meta>\n",
+        id,
+        person.role_code(default).unwrap_or(default)
+    ));
+    if let Some(file_as) = &person.file_as {
+        metadata.push_str(&format!("        <meta refines=\"#{}\" property=\"file-as\">{}</meta>\n", id, xml_escape(file_as)));
+    }
+    metadata
+}
+
+/// The book's cover image, packaged next to the chapters.
+struct Cover {
+    source: PathBuf,
+    file_name: String,
+    media_type: &'static str,
+}
+
+/// The configured cover in `assets/images/`, when it exists and is an image
+/// format e-readers support.
+fn find_cover(book_dir: &Path, config: &Config) -> Option<Cover> {
+    let source = book_dir.join("assets").join("images").join(config.cover.as_ref()?);
+    if !source.is_file() {
+        return None;
+    }
+    let media_type = match assets::media_type(&source)? {
+        media_type @ ("image/png" | "image/jpeg" | "image/gif" | "image/webp" | "image/svg+xml") => media_type,
+        // Such as a PDF
+        _ => return None,
+    };
+    let ext = source.extension()?.to_str()?.to_lowercase();
+    Some(Cover { source, file_name: format!("cover.{}", ext), media_type })
+}
+
+/// The first page of the book, showing the cover image.
+fn generate_cover_page(config: &Config, cover: &Cover) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops" lang="{0}" xml:lang="{0}">
+<head>
+    <title>Cover</title>
+    <style>
+        body {{ margin: 0; padding: 0; text-align: center; }}
+        img {{ max-width: 100%; max-height: 100vh; }}
+    </style>
+</head>
+<body epub:type="cover">
+    <img src="{1}" alt="{2}"/>
+</body>
+</html>
+"#,
+        xml_escape(config.language.as_deref().unwrap_or("en")),
+        cover.file_name,
+        xml_escape(&config.title)
+    )
+}
+
+fn generate_opf(
+    config: &Config,
+    files: &[MarkdownFile],
+    media: &BTreeMap<String, PathBuf>,
+    cover: Option<&Cover>,
+    modified: DateTime<Utc>,
+) -> Result<String> {
     let mut opf = String::from(r#"<?xml version="1.0" encoding="UTF-8"?>
 <package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="bookid">
     <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
         <dc:title>"#);
     opf.push_str(&xml_escape(&config.title));
-    opf.push_str(r#"</dc:title>
-        <dc:creator>"#);
-    opf.push_str(&xml_escape(&config.author));
-    opf.push_str(r#"</dc:creator>
-        <dc:language>"#);
-    opf.push_str(&config.language.as_deref().unwrap_or("en"));
+    opf.push_str("</dc:title>\n");
+    opf.push_str(&publication_metadata(config));
+    opf.push_str(r#"        <dc:language>"#);
+    opf.push_str(config.language.as_deref().unwrap_or("en"));
     opf.push_str(r#"</dc:language>
-        <dc:identifier id="bookid">urn:uuid:");
-    opf.push_str(&uuid::Uuid::new_v4().to_string());
-    opf.push_str(r#"</dc:identifier>
-    </metadata>
+        <dc:identifier id="bookid">"#);
+    opf.push_str(&xml_escape(&book_identifier(config)));
+    opf.push_str("</dc:identifier>\n");
+    // The ISBN as well, when the book is known by another identifier
+    if let Some(isbn) = config.isbn().filter(|isbn| *isbn != book_identifier(config)) {
+        opf.push_str(&format!("        <dc:identifier id=\"isbn\">{}</dc:identifier>\n", isbn));
+    }
+    opf.push_str(r#"        <meta property="dcterms:modified">"#);
+    opf.push_str(&modified.format("%Y-%m-%dT%H:%M:%SZ").to_string());
+    opf.push_str("</meta>\n");
+    if cover.is_some() {
+        // For EPUB 2 readers, such as older Kindles
+        opf.push_str("        <meta name=\"cover\" content=\"cover-image\"/>\n");
+    }
+    opf.push_str(r#"    </metadata>
     <manifest>
+        <item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>
         <item id="ncx" href="toc.ncx" media-type="application/x-dtbncx+xml"/>
         <item id="style" href="style.css" media-type="text/css"/>
 "#);
+    if let Some(cover) = cover {
+        opf.push_str(&format!(
+            "        <item id=\"cover-image\" href=\"{}\" media-type=\"{}\" properties=\"cover-image\"/>\n",
+            cover.file_name,
+            cover.media_type
+        ));
+        opf.push_str("        <item id=\"cover-page\" href=\"cover.xhtml\" media-type=\"application/xhtml+xml\"/>\n");
+    }
     
     for (index, _) in files.iter().enumerate() {
         opf.push_str(&format!(
@@ -151,16 +419,35 @@ fn generate_opf(config: &Config, files: &[MarkdownFile]) -> Result<String> {
         ));
     }
     
+    for (index, media_path) in media.keys().enumerate() {
+        let media_type = assets::media_type(Path::new(media_path)).unwrap_or("application/octet-stream");
+        opf.push_str(&format!(
+            "        <item id=\"media{}\" href=\"{}\" media-type=\"{}\"/>\n",
+            index + 1,
+            assets::url_path(Path::new(media_path)),
+            media_type
+        ));
+    }
+    
     opf.push_str(r#"    </manifest>
     <spine toc="ncx">
 "#);
+    if cover.is_some() {
+        opf.push_str("        <itemref idref=\"cover-page\"/>\n");
+    }
     
     for (index, _) in files.iter().enumerate() {
         opf.push_str(&format!("        <itemref idref=\"chapter{}\"/>\n", index + 1));
     }
     
-    opf.push_str(r#"    </spine>
-</package>"#);
+    opf.push_str("    </spine>\n");
+    if cover.is_some() {
+        opf.push_str(r#"    <guide>
+        <reference type="cover" title="Cover" href="cover.xhtml"/>
+    </guide>
+"#);
+    }
+    opf.push_str("</package>");
     
     Ok(opf)
 }
@@ -169,8 +456,8 @@ fn generate_ncx(config: &Config, files: &[MarkdownFile]) -> Result<String> {
     let mut ncx = String::from(r#"<?xml version="1.0" encoding="UTF-8"?>
 <ncx xmlns="http://www.daisy.org/z3986/2005/ncx/" version="2005-1">
     <head>
-        <meta name="dtb:uid" content="urn:uuid:");
-    ncx.push_str(&uuid::Uuid::new_v4().to_string());
+        <meta name="dtb:uid" content=""#);
+    ncx.push_str(&xml_escape(&book_identifier(config)));
     ncx.push_str(r#""/>
         <meta name="dtb:depth" content="1"/>
         <meta name="dtb:totalPageCount" content="0"/>
@@ -212,6 +499,82 @@ fn generate_ncx(config: &Config, files: &[MarkdownFile]) -> Result<String> {
     Ok(ncx)
 }
 
+/// The EPUB 3 navigation document: the table of contents, landmarks, and a
+/// page list when chapters mark page breaks (`epub:type="pagebreak"` or
+/// `role="doc-pagebreak"` with an `id`).
+fn generate_nav(config: &Config, files: &[MarkdownFile], cover: Option<&Cover>) -> String {
+    let language = xml_escape(config.language.as_deref().unwrap_or("en"));
+    let mut nav = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops" lang="{0}" xml:lang="{0}">
+<head>
+    <title>{1}</title>
+    <link rel="stylesheet" type="text/css" href="style.css"/>
+</head>
+<body>
+    <nav epub:type="toc" id="toc">
+        <h1>Contents</h1>
+        <ol>
+"#,
+        language,
+        xml_escape(&config.title)
+    );
+    
+    for (index, file) in files.iter().enumerate() {
+        nav.push_str(&format!(
+            "            <li><a href=\"chapter{:03}.xhtml\">{}</a></li>\n",
+            index + 1,
+            xml_escape(&chapter_title(file, index))
+        ));
+    }
+    nav.push_str("        </ol>\n    </nav>\n");
+    
+    nav.push_str(r#"    <nav epub:type="landmarks" hidden="hidden">
+        <h2>Landmarks</h2>
+        <ol>
+"#);
+    if cover.is_some() {
+        nav.push_str("            <li><a epub:type=\"cover\" href=\"cover.xhtml\">Cover</a></li>\n");
+    }
+    nav.push_str("            <li><a epub:type=\"toc\" href=\"nav.xhtml#toc\">Contents</a></li>\n");
+    if !files.is_empty() {
+        nav.push_str("            <li><a epub:type=\"bodymatter\" href=\"chapter001.xhtml\">Start of content</a></li>\n");
+    }
+    nav.push_str("        </ol>\n    </nav>\n");
+    
+    let page_break_re = regex::Regex::new(r#"<[^>]*\b(?:epub:type|role)="(?:pagebreak|doc-pagebreak)"[^>]*>"#).unwrap();
+    let id_re = regex::Regex::new(r#"\bid="([^"]+)""#).unwrap();
+    let label_re = regex::Regex::new(r#"\b(?:title|aria-label)="([^"]+)""#).unwrap();
+    let mut pages = String::new();
+    for (index, file) in files.iter().enumerate() {
+        for page_break in page_break_re.find_iter(&file.html) {
+            let id = match id_re.captures(page_break.as_str()) {
+                Some(id) => id[1].to_string(),
+                None => continue,
+            };
+            let label = label_re.captures(page_break.as_str()).map_or_else(|| id.clone(), |label| label[1].to_string());
+            pages.push_str(&format!(
+                "            <li><a href=\"chapter{:03}.xhtml#{}\">{}</a></li>\n",
+                index + 1,
+                id,
+                label
+            ));
+        }
+    }
+    if !pages.is_empty() {
+        nav.push_str(r#"    <nav epub:type="page-list" hidden="hidden">
+        <h2>Pages</h2>
+        <ol>
+"#);
+        nav.push_str(&pages);
+        nav.push_str("        </ol>\n    </nav>\n");
+    }
+    
+    nav.push_str("</body>\n</html>\n");
+    nav
+}
+
 fn xml_escape(s: &str) -> String {
     s.replace("&", "&amp;")
         .replace("<", "&lt;")
@@ -233,3 +596,63 @@ fn sanitize_filename(name: &str) -> String {
         .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' { c } else { '-' })
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    /// A new book from `papyrus init` in a scratch directory.
+    async fn scratch_book(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("papyrus-epub-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        crate::config::init_project(&dir).await.unwrap();
+        dir
+    }
+
+    fn package_document(epub: &[u8]) -> String {
+        let mut archive = zip::ZipArchive::new(std::io::Cursor::new(epub)).unwrap();
+        let mut opf = String::new();
+        archive.by_name("OEBPS/content.opf").unwrap().read_to_string(&mut opf).unwrap();
+        opf
+    }
+
+    #[tokio::test]
+    async fn building_twice_gives_the_same_book() {
+        let dir = scratch_book("reproducible").await;
+        let content_dir = dir.join("content");
+        let first = fs::read(generate_epub(&dir, &content_dir).await.unwrap()).unwrap();
+
+        // Rewrite a chapter as it was, and wait for the ZIP clock (2 seconds) to move on
+        let chapter = content_dir.join("01-introduction.md");
+        fs::write(&chapter, fs::read(&chapter).unwrap()).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(2100));
+        let second = fs::read(generate_epub(&dir, &content_dir).await.unwrap()).unwrap();
+        assert!(first == second, "rebuilding changed the EPUB");
+
+        let config = Config::load(&dir).unwrap();
+        let identifier = config.identifier.clone().unwrap();
+        assert!(identifier.starts_with("urn:uuid:"), "{}", identifier);
+        assert!(package_document(&second).contains(&format!("<dc:identifier id=\"bookid\">{}</dc:identifier>", identifier)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn identifiers_are_stable_uris() {
+        let mut config = Config::default();
+        let derived = book_identifier(&config);
+        assert!(derived.starts_with("urn:uuid:"), "{}", derived);
+        assert_eq!(book_identifier(&config), derived);
+
+        config.metadata = Some(crate::config::MetadataConfig {
+            isbn: Some("978-3-16-148410-0".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(book_identifier(&config), "urn:isbn:9783161484100");
+
+        config.identifier = Some("85AA06C9-05FA-5EBE-8C9C-F53B530B80E2".to_string());
+        assert_eq!(book_identifier(&config), "urn:uuid:85aa06c9-05fa-5ebe-8c9c-f53b530b80e2");
+        config.identifier = Some("https://example.com/books/guide".to_string());
+        assert_eq!(book_identifier(&config), "https://example.com/books/guide");
+    }
+}
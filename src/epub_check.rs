@@ -0,0 +1,484 @@
+use roxmltree::{Document, Node, ParsingOptions};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use zip::{CompressionMethod, ZipArchive};
+use crate::assets;
+use crate::error::Result;
+
+const OPF_NS: &str = "http://www.idpf.org/2007/opf";
+const DC_NS: &str = "http://purl.org/dc/elements/1.1/";
+const XLINK_NS: &str = "http://www.w3.org/1999/xlink";
+
+/// Attributes of XHTML content pointing at other files of the book.
+const LINK_ATTRIBUTES: &[&str] = &["href", "src", "poster", "data"];
+
+/// Something wrong with an EPUB, and where.
+pub struct Problem {
+    pub file: String,
+    pub line: Option<u32>,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{}: {}", self.file, line, self.message),
+            None => write!(f, "{}: {}", self.file, self.message),
+        }
+    }
+}
+
+/// Validates the EPUB at `path`: the container layout, the package's
+/// metadata, manifest and spine, and the well-formedness, IDs and links of
+/// its XHTML documents.
+pub fn check(path: &Path) -> Result<Vec<Problem>> {
+    let mut archive = ZipArchive::new(File::open(path)?)?;
+    let mut problems = Vec::new();
+
+    // The mimetype must be first and uncompressed, so it can be sniffed
+    {
+        let mut first = archive.by_index(0)?;
+        let mut content = String::new();
+        first.read_to_string(&mut content)?;
+        if first.name() != "mimetype" {
+            problems.push(problem("mimetype", None, format!("must be the first file of the archive, found {} instead", first.name())));
+        } else {
+            if first.compression() != CompressionMethod::Stored {
+                problems.push(problem("mimetype", None, "must be stored without compression"));
+            }
+            if content != "application/epub+zip" {
+                problems.push(problem("mimetype", None, "must contain exactly \"application/epub+zip\""));
+            }
+        }
+    }
+
+    let mut files = BTreeMap::new();
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index)?;
+        if entry.is_dir() {
+            continue;
+        }
+        let mut content = Vec::new();
+        entry.read_to_end(&mut content)?;
+        files.insert(entry.name().to_string(), content);
+    }
+    if !files.contains_key("mimetype") {
+        problems.push(problem("mimetype", None, "missing"));
+    }
+
+    let opf_path = match package_path(&files, &mut problems) {
+        Some(opf_path) => opf_path,
+        None => return Ok(problems),
+    };
+    let package = match parse(&files, &opf_path, &mut problems) {
+        Some(package) => package,
+        None => return Ok(problems),
+    };
+    let manifest = check_package(&package, &opf_path, &files, &mut problems);
+    check_content(&files, &manifest, &mut problems);
+
+    Ok(problems)
+}
+
+/// A manifest item, with its href resolved to a file in the archive.
+struct Item {
+    file: String,
+    media_type: String,
+}
+
+/// Finds the package document through `META-INF/container.xml`.
+fn package_path(files: &BTreeMap<String, Vec<u8>>, problems: &mut Vec<Problem>) -> Option<String> {
+    const CONTAINER: &str = "META-INF/container.xml";
+    let text = match files.get(CONTAINER) {
+        Some(bytes) => String::from_utf8_lossy(bytes).into_owned(),
+        None => {
+            problems.push(problem(CONTAINER, None, "missing"));
+            return None;
+        }
+    };
+    let container = match Document::parse(&text) {
+        Ok(container) => container,
+        Err(e) => {
+            problems.push(problem(CONTAINER, Some(e.pos().row), e.to_string()));
+            return None;
+        }
+    };
+
+    let rootfile = container.descendants().find(|node| node.has_tag_name("rootfile"));
+    match rootfile.and_then(|rootfile| rootfile.attribute("full-path")) {
+        Some(full_path) if files.contains_key(full_path) => Some(full_path.to_string()),
+        Some(full_path) => {
+            let line = line_of(&container, rootfile.unwrap());
+            problems.push(problem(CONTAINER, Some(line), format!("package document {} is missing", full_path)));
+            None
+        }
+        None => {
+            problems.push(problem(CONTAINER, None, "no rootfile with a full-path"));
+            None
+        }
+    }
+}
+
+/// Checks the metadata, manifest and spine of the package document, and
+/// returns the manifest.
+fn check_package(document: &Document, opf_path: &str, files: &BTreeMap<String, Vec<u8>>, problems: &mut Vec<Problem>) -> HashMap<String, Item> {
+    let root = document.root_element();
+    let opf_dir = parent_dir(opf_path);
+    let mut report = |node: Node, message: String| problems.push(problem(opf_path, Some(line_of(document, node)), message));
+
+    // Metadata
+    let metadata = root.children().find(|node| node.has_tag_name((OPF_NS, "metadata")));
+    let dc = |name: &str| {
+        metadata
+            .into_iter()
+            .flat_map(|metadata| metadata.children())
+            .filter(move |node| node.has_tag_name((DC_NS, name)))
+            .filter(|node| !node.text().unwrap_or_default().trim().is_empty())
+            .collect::<Vec<_>>()
+    };
+    for required in ["title", "language", "identifier"] {
+        if dc(required).is_empty() {
+            report(metadata.unwrap_or(root), format!("required metadata dc:{} is missing", required));
+        }
+    }
+    match root.attribute("unique-identifier") {
+        Some(id) if !dc("identifier").iter().any(|node| node.attribute("id") == Some(id)) => {
+            report(root, format!("unique-identifier \"{}\" is not the id of a dc:identifier", id));
+        }
+        Some(_) => {}
+        None => report(root, "package has no unique-identifier".to_string()),
+    }
+
+    let epub3 = root.attribute("version").is_some_and(|version| version.starts_with('3'));
+    if epub3 {
+        let modified = metadata
+            .into_iter()
+            .flat_map(|metadata| metadata.children())
+            .find(|node| node.has_tag_name((OPF_NS, "meta")) && node.attribute("property") == Some("dcterms:modified"));
+        let timestamp_re = regex::Regex::new(r"^\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}Z$").unwrap();
+        match modified {
+            Some(node) if !timestamp_re.is_match(node.text().unwrap_or_default().trim()) => {
+                report(node, "dcterms:modified must look like 2024-01-31T12:00:00Z".to_string());
+            }
+            Some(_) => {}
+            None => report(metadata.unwrap_or(root), "required metadata dcterms:modified is missing".to_string()),
+        }
+    }
+
+    // Manifest
+    let mut manifest = HashMap::new();
+    let mut nav_items = 0;
+    let manifest_node = root.children().find(|node| node.has_tag_name((OPF_NS, "manifest")));
+    for item in manifest_node.iter().flat_map(|node| node.children()).filter(|node| node.has_tag_name((OPF_NS, "item"))) {
+        let (id, href, media_type) = match (item.attribute("id"), item.attribute("href"), item.attribute("media-type")) {
+            (Some(id), Some(href), Some(media_type)) => (id, href, media_type),
+            _ => {
+                report(item, "manifest item needs an id, href and media-type".to_string());
+                continue;
+            }
+        };
+        if item.attribute("properties").is_some_and(|properties| properties.split_whitespace().any(|p| p == "nav")) {
+            nav_items += 1;
+        }
+
+        let file = match resolve(&opf_dir, href) {
+            Some(file) => file,
+            None => {
+                report(item, format!("manifest item \"{}\" points outside the book: {}", id, href));
+                continue;
+            }
+        };
+        if !files.contains_key(&file) {
+            report(item, format!("manifest item \"{}\" points to a missing file: {}", id, href));
+        }
+        if manifest.insert(id.to_string(), Item { file, media_type: media_type.to_string() }).is_some() {
+            report(item, format!("duplicate manifest id \"{}\"", id));
+        }
+    }
+    if epub3 && nav_items != 1 {
+        report(manifest_node.unwrap_or(root), format!("EPUB 3 needs exactly one item with properties=\"nav\", found {}", nav_items));
+    }
+
+    // Spine
+    let spine = root.children().find(|node| node.has_tag_name((OPF_NS, "spine")));
+    match spine {
+        Some(spine) => {
+            if let Some(toc) = spine.attribute("toc")
+                && !manifest.contains_key(toc)
+            {
+                report(spine, format!("spine toc \"{}\" is not a manifest id", toc));
+            }
+            let itemrefs: Vec<_> = spine.children().filter(|node| node.has_tag_name((OPF_NS, "itemref"))).collect();
+            if itemrefs.is_empty() {
+                report(spine, "the spine is empty".to_string());
+            }
+            for itemref in itemrefs {
+                match itemref.attribute("idref") {
+                    Some(idref) if !manifest.contains_key(idref) => {
+                        report(itemref, format!("spine itemref \"{}\" is not a manifest id", idref));
+                    }
+                    Some(_) => {}
+                    None => report(itemref, "spine itemref has no idref".to_string()),
+                }
+            }
+        }
+        None => report(root, "package has no spine".to_string()),
+    }
+
+    // Everything shipped must be declared
+    let manifested: HashSet<&str> = manifest.values().map(|item| item.file.as_str()).collect();
+    for file in files.keys() {
+        if file != "mimetype" && !file.starts_with("META-INF/") && file != opf_path && !manifested.contains(file.as_str()) {
+            problems.push(problem(file, None, "not listed in the manifest"));
+        }
+    }
+
+    manifest
+}
+
+/// Checks that XHTML documents and the NCX are well-formed, that IDs are
+/// unique within each document, and that their links resolve.
+fn check_content(files: &BTreeMap<String, Vec<u8>>, manifest: &HashMap<String, Item>, problems: &mut Vec<Problem>) {
+    let mut documents: Vec<(&str, Document)> = Vec::new();
+    let mut sorted: Vec<&Item> = manifest.values().collect();
+    sorted.sort_by(|a, b| a.file.cmp(&b.file));
+    for item in sorted {
+        let is_markup = item.media_type == "application/xhtml+xml" || item.media_type == "application/x-dtbncx+xml";
+        if is_markup
+            && files.contains_key(&item.file)
+            && let Some(document) = parse(files, &item.file, problems)
+        {
+            documents.push((&item.file, document));
+        }
+    }
+
+    let mut ids: HashMap<&str, HashSet<String>> = HashMap::new();
+    for (file, document) in &documents {
+        let mut seen = HashSet::new();
+        for node in document.descendants().filter(Node::is_element) {
+            if let Some(id) = node.attribute("id")
+                && !seen.insert(id.to_string())
+            {
+                problems.push(problem(file, Some(line_of(document, node)), format!("duplicate id \"{}\"", id)));
+            }
+        }
+        ids.insert(file, seen);
+    }
+
+    for (file, document) in &documents {
+        let dir = parent_dir(file);
+        for node in document.descendants().filter(Node::is_element) {
+            let links = LINK_ATTRIBUTES
+                .iter()
+                .filter_map(|name| node.attribute(*name))
+                .chain(node.attribute((XLINK_NS, "href")));
+            for link in links {
+                if link.is_empty() || is_external(link) {
+                    continue;
+                }
+                let (target, fragment) = match link.split_once('#') {
+                    Some((target, fragment)) => (target, Some(fragment)),
+                    None => (link, None),
+                };
+                let target_file = if target.is_empty() {
+                    Some(file.to_string())
+                } else {
+                    resolve(&dir, target)
+                };
+                let line = Some(line_of(document, node));
+                match target_file {
+                    Some(target_file) if files.contains_key(&target_file) => {
+                        let fragment = fragment.filter(|fragment| !fragment.is_empty());
+                        if let (Some(fragment), Some(known)) = (fragment, ids.get(target_file.as_str()))
+                            && !known.contains(&assets::percent_decode(fragment))
+                        {
+                            problems.push(problem(file, line, format!("link to a missing id: {}", link)));
+                        }
+                    }
+                    _ => problems.push(problem(file, line, format!("link to a missing file: {}", link))),
+                }
+            }
+        }
+    }
+}
+
+/// Reads `file` from the archive as XML, reporting where it isn't.
+fn parse<'a>(files: &'a BTreeMap<String, Vec<u8>>, file: &str, problems: &mut Vec<Problem>) -> Option<Document<'a>> {
+    let text = match std::str::from_utf8(&files[file]) {
+        Ok(text) => text,
+        Err(_) => {
+            problems.push(problem(file, None, "not valid UTF-8"));
+            return None;
+        }
+    };
+    // XHTML documents start with <!DOCTYPE html>
+    let options = ParsingOptions { allow_dtd: true, ..ParsingOptions::default() };
+    match Document::parse_with_options(text, options) {
+        Ok(document) => Some(document),
+        Err(e) => {
+            problems.push(problem(file, Some(e.pos().row), format!("not well-formed: {}", e)));
+            None
+        }
+    }
+}
+
+fn problem(file: &str, line: Option<u32>, message: impl Into<String>) -> Problem {
+    Problem { file: file.to_string(), line, message: message.into() }
+}
+
+fn line_of(document: &Document, node: Node) -> u32 {
+    document.text_pos_at(node.range().start).row
+}
+
+fn is_external(link: &str) -> bool {
+    let scheme_end = link.find(':');
+    scheme_end.is_some_and(|end| !link[..end].contains(['/', '#', '?']))
+}
+
+fn parent_dir(file: &str) -> String {
+    file.rsplit_once('/').map(|(dir, _)| format!("{}/", dir)).unwrap_or_default()
+}
+
+/// Resolves `href` against `dir` to a path inside the archive, or `None`
+/// when it climbs out of it.
+fn resolve(dir: &str, href: &str) -> Option<String> {
+    let href = href.split(['?', '#']).next().unwrap_or_default();
+    let mut parts: Vec<String> = Vec::new();
+    for part in format!("{}{}", dir, assets::percent_decode(href)).split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop()?;
+            }
+            part => parts.push(part.to_string()),
+        }
+    }
+    Some(parts.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::ZipWriter;
+    use zip::write::FileOptions;
+
+    const CONTAINER_XML: &str = r#"<?xml version="1.0"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+    <rootfiles><rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/></rootfiles>
+</container>"#;
+
+    const PACKAGE: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="bookid">
+    <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+        <dc:title>Test</dc:title>
+        <dc:language>en</dc:language>
+        <dc:identifier id="bookid">urn:uuid:85aa06c9-05fa-5ebe-8c9c-f53b530b80e2</dc:identifier>
+        <meta property="dcterms:modified">2024-01-01T00:00:00Z</meta>
+    </metadata>
+    <manifest>
+        <item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>
+        <item id="chapter1" href="chapter001.xhtml" media-type="application/xhtml+xml"/>
+    </manifest>
+    <spine><itemref idref="chapter1"/></spine>
+</package>"#;
+
+    const NAV: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">
+<head><title>Contents</title></head>
+<body><nav epub:type="toc"><ol><li><a href="chapter001.xhtml#start">Start</a></li></ol></nav></body>
+</html>"#;
+
+    /// A chapter with `body` as its content.
+    fn chapter(body: &str) -> String {
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<html xmlns=\"http://www.w3.org/1999/xhtml\">\n<head><title>One</title></head>\n<body>\n{}\n</body>\n</html>",
+            body
+        )
+    }
+
+    /// Writes an EPUB with the given mimetype and files, and checks it.
+    fn check_files(mimetype: &str, files: &[(&str, &str)]) -> Vec<String> {
+        let path = std::env::temp_dir().join(format!("papyrus-check-{}-{:x}.epub", std::process::id(), archive_id(files)));
+        let mut zip = ZipWriter::new(File::create(&path).unwrap());
+        zip.start_file("mimetype", FileOptions::default().compression_method(CompressionMethod::Stored)).unwrap();
+        zip.write_all(mimetype.as_bytes()).unwrap();
+        for (name, content) in files {
+            zip.start_file(*name, FileOptions::default()).unwrap();
+            zip.write_all(content.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+
+        let problems = check(&path).unwrap().iter().map(|problem| problem.to_string()).collect();
+        std::fs::remove_file(&path).unwrap();
+        problems
+    }
+
+    /// Keeps the archives of tests running at the same time apart.
+    fn archive_id(files: &[(&str, &str)]) -> u64 {
+        use std::hash::{DefaultHasher, Hash, Hasher};
+        let mut hasher = DefaultHasher::new();
+        files.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn book_with_chapter(body: &str) -> Vec<String> {
+        let chapter = chapter(body);
+        check_files("application/epub+zip", &[
+            ("META-INF/container.xml", CONTAINER_XML),
+            ("OEBPS/content.opf", PACKAGE),
+            ("OEBPS/nav.xhtml", NAV),
+            ("OEBPS/chapter001.xhtml", &chapter),
+        ])
+    }
+
+    #[test]
+    fn accepts_a_valid_book() {
+        assert_eq!(book_with_chapter("<h1 id=\"start\">One</h1>"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn reports_a_bad_mimetype() {
+        let chapter = chapter("<h1 id=\"start\">One</h1>");
+        let problems = check_files("application/zip", &[
+            ("META-INF/container.xml", CONTAINER_XML),
+            ("OEBPS/content.opf", PACKAGE),
+            ("OEBPS/nav.xhtml", NAV),
+            ("OEBPS/chapter001.xhtml", &chapter),
+        ]);
+        assert_eq!(problems, ["mimetype: must contain exactly \"application/epub+zip\""]);
+    }
+
+    #[test]
+    fn reports_files_missing_from_the_manifest() {
+        let chapter = chapter("<h1 id=\"start\">One</h1>");
+        let problems = check_files("application/epub+zip", &[
+            ("META-INF/container.xml", CONTAINER_XML),
+            ("OEBPS/content.opf", PACKAGE),
+            ("OEBPS/nav.xhtml", NAV),
+            ("OEBPS/chapter001.xhtml", &chapter),
+            ("OEBPS/images/extra.png", "png"),
+        ]);
+        assert_eq!(problems, ["OEBPS/images/extra.png: not listed in the manifest"]);
+    }
+
+    #[test]
+    fn reports_duplicate_ids() {
+        let problems = book_with_chapter("<h1 id=\"start\">One</h1>\n<p id=\"start\">Again</p>");
+        assert_eq!(problems, ["OEBPS/chapter001.xhtml:6: duplicate id \"start\""]);
+    }
+
+    #[test]
+    fn reports_links_to_missing_ids_and_files() {
+        let problems = book_with_chapter(
+            "<h1 id=\"start\">One</h1>\n<p><a href=\"#nowhere\">here</a> <a href=\"chapter001.xhtml#start\">ok</a></p>\n<p><a href=\"chapter002.xhtml\">next</a></p>",
+        );
+        assert_eq!(problems, [
+            "OEBPS/chapter001.xhtml:6: link to a missing id: #nowhere",
+            "OEBPS/chapter001.xhtml:7: link to a missing file: chapter002.xhtml",
+        ]);
+    }
+}
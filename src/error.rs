@@ -21,12 +21,14 @@ pub enum PapyrusError {
     Toml(#[from] toml::de::Error),
     
     #[error("EPUB generation error: {0}")]
-    #[allow(dead_code)]
     Epub(String),
     
     #[error("PDF generation error: {0}")]
     Pdf(String),
     
+    #[error("Preprocessor error: {0}")]
+    Preprocessor(String),
+    
     #[error("Asset error: {0}")]
     Asset(String),
     
@@ -35,6 +37,9 @@ pub enum PapyrusError {
     
     #[error("Walkdir error: {0}")]
     Walkdir(#[from] walkdir::Error),
+    
+    #[error("Watch error: {0}")]
+    Watch(#[from] notify::Error),
 }
 
 pub type Result<T> = std::result::Result<T, PapyrusError>;
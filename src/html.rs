@@ -1,93 +1,510 @@
-use tera::{Tera, Context};
-use std::path::Path;
+use tera::Context;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use crate::assets;
 use crate::error::{PapyrusError, Result};
-use crate::markdown::MarkdownFile;
-use crate::config::Config;
+use crate::markdown::{MarkdownFile, OutputFormat, add_heading_ids, chapter_slugs, chapter_title, html_to_text};
+use crate::config::{Config, THEME_HTML_PAGE_TEMPLATE, THEME_HTML_INDEX_TEMPLATE};
+use crate::pwa;
+use crate::seo::{self, Seo};
+use crate::theme;
+use crate::versions::{self, Versions};
 use std::fs;
-use syntect::easy::HighlightLines;
+use syntect::html::{ClassStyle, ClassedHTMLGenerator, css_for_theme_with_class_style};
 use syntect::parsing::SyntaxSet;
-use syntect::highlighting::{ThemeSet, Style};
-use syntect::util::{as_24_bit_terminal_escaped, LinesWithEndings};
+use syntect::highlighting::ThemeSet;
+use syntect::util::LinesWithEndings;
 
-pub async fn generate_html<P: AsRef<Path>>(book_dir: P, content_dir: P) -> Result<()> {
-    let book_dir = book_dir.as_ref();
-    let content_dir = content_dir.as_ref();
+const SEARCH_JS: &str = include_str!("../templates/search.js");
+const READER_JS: &str = include_str!("../templates/reader.js");
+const READER_CSS: &str = include_str!("../templates/reader.css");
+const PRINT_CSS: &str = include_str!("../templates/print.css");
+const PAGE_CSS: &str = include_str!("../templates/pdf_page.css");
+
+/// Prefix of the classes syntax highlighting puts on code.
+const HIGHLIGHT_CLASS_STYLE: ClassStyle = ClassStyle::SpacedPrefixed { prefix: "hl-" };
+
+/// The syntect theme colouring code in each reader colour scheme.
+const HIGHLIGHT_THEMES: &[(&str, &str)] = &[
+    ("light", "InspiredGitHub"),
+    ("dark", "base16-ocean.dark"),
+    ("sepia", "Solarized (light)"),
+];
+
+/// Work kept between builds of the same book, so that `serve` only redoes
+/// what a change affects.
+#[derive(Default)]
+pub struct BuildCache {
+    /// Rendered and highlighted chapters, by the Markdown they came from
+    chapters: HashMap<String, String>,
+    /// Whether the export's copies of images, fonts and theme files are up to date
+    assets_current: bool,
+    /// Chapters rendered by the last build
+    rendered: usize,
+}
+
+impl BuildCache {
+    /// Takes note of the files changed since the last build. Anything but
+    /// chapter Markdown may be an asset, which are then copied again.
+    pub fn changed(&mut self, paths: &[PathBuf]) {
+        if paths.iter().any(|path| path.extension().is_none_or(|ext| ext != "md")) {
+            self.assets_current = false;
+        }
+    }
     
+    /// How many chapters the last build rendered, and how many the book has.
+    pub fn rendered(&self) -> (usize, usize) {
+        (self.rendered, self.chapters.len())
+    }
+    
+    /// Renders and highlights `files`, reusing the HTML of chapters whose
+    /// Markdown hasn't changed.
+    fn render(&mut self, files: Vec<MarkdownFile>) -> Result<Vec<MarkdownFile>> {
+        let mut previous = std::mem::take(&mut self.chapters);
+        self.rendered = 0;
+        files
+            .into_iter()
+            .map(|mut file| {
+                file.html = match previous.remove(&file.content) {
+                    Some(html) => html,
+                    None => {
+                        file.render(OutputFormat::Html)?;
+                        self.rendered += 1;
+                        highlight_code_blocks(&file.html)
+                    }
+                };
+                self.chapters.insert(file.content.clone(), file.html.clone());
+                Ok(file)
+            })
+            .collect()
+    }
+}
+
+/// Writes the HTML book and returns the path of its main page.
+pub async fn generate_html<P: AsRef<Path>>(book_dir: P, content_dir: P, multi_page: bool, standalone: bool) -> Result<PathBuf> {
+    generate_html_cached(book_dir.as_ref(), content_dir.as_ref(), multi_page, standalone, &mut BuildCache::default()).await
+}
+
+/// Like [`generate_html`], reusing the work of earlier builds kept in `cache`.
+pub async fn generate_html_cached(book_dir: &Path, content_dir: &Path, multi_page: bool, standalone: bool, cache: &mut BuildCache) -> Result<PathBuf> {
     // Load config
     let config = Config::load(book_dir)?;
     
-    // Collect and parse markdown files
+    // Collect and parse markdown files, with syntax highlighting
     let md_files = crate::markdown::collect_markdown_files(content_dir, config.md_file_list.as_deref())?;
-    let parsed_files = crate::markdown::parse_markdown_files(&md_files)?;
+    let loaded_files = crate::markdown::load_markdown_files(book_dir, &md_files, &config, OutputFormat::Html)?;
+    let processed_files = cache.render(loaded_files)?;
+    
+    // Until this build has finished, assets may be missing from the export
+    let refresh_assets = !cache.assets_current;
+    cache.assets_current = false;
     
-    // Process markdown with syntax highlighting
-    let processed_files: Vec<MarkdownFile> = parsed_files
+    // A standalone file is always a single page
+    let multi_page = !standalone && (multi_page || config.html.as_ref().and_then(|h| h.multi_page).unwrap_or(false));
+    let slugs = chapter_slugs(&processed_files);
+    let processed_files = add_heading_ids(processed_files, &slugs, multi_page);
+    
+    let search_index = if config.html.as_ref().and_then(|h| h.search).unwrap_or(true) {
+        Some(build_search_index(&processed_files, &slugs, multi_page)?)
+    } else {
+        None
+    };
+    
+    // Each edition of a versioned book goes into its own folder
+    let version = if !standalone && config.html.as_ref().and_then(|h| h.versioned).unwrap_or(false) {
+        let version = config.version.as_deref().unwrap_or_default();
+        versions::validate(version)?;
+        Some(version)
+    } else {
+        None
+    };
+    
+    let export_dir = match version {
+        Some(version) => book_dir.join("export").join("html").join(version),
+        None if multi_page => book_dir.join("export").join("html"),
+        None => book_dir.join("export"),
+    };
+    fs::create_dir_all(&export_dir)?;
+    
+    let assets_dir = book_dir.join("assets");
+    let roots = [content_dir, &assets_dir, book_dir];
+    
+    // Everything this build writes to the export besides the pages
+    let mut written = BTreeSet::new();
+    // Files copied into the export once the pages are ready (path there to
+    // source file)
+    let mut copies = BTreeMap::new();
+    
+    // Bring images and other local files along, so the export can be published
+    // as-is, or embed them when everything has to fit in one file
+    let processed_files = processed_files
         .into_iter()
         .map(|mut file| {
-            file.html = highlight_code_blocks(&file.html);
-            file
+            let chapter_dir = file.path.parent().unwrap_or(content_dir).to_path_buf();
+            file.html = if standalone {
+                assets::inline_referenced_assets(&file.html, &chapter_dir, &roots)?
+            } else {
+                assets::collect_referenced_assets(&file.html, &chapter_dir, &roots, &mut copies)?
+            };
+            Ok(file)
         })
-        .collect();
+        .collect::<Result<Vec<_>>>()?;
+    
+    let chapters = theme::chapters(&processed_files, &slugs, |_, slug| {
+        if multi_page { format!("{}.html", slug) } else { format!("#{}", slug) }
+    });
+    let toc = theme::toc(&chapters, 3);
+    let mut context = theme::base_context(book_dir, &config, &chapters, &toc);
+    let cover = cover_url(book_dir, &config, standalone, &mut copies)?;
+    context.insert("cover", &cover);
+    context.insert("static_url", "static/");
+    context.insert("search", &search_index.is_some());
     
-    // Load theme template
-    let theme_path = book_dir.join("assets").join("theme-html.html");
-    if !theme_path.exists() {
-        return Err(PapyrusError::Asset(format!(
-            "Theme file not found: {}",
-            theme_path.display()
-        )));
+    let mut seo = Seo::new(&config, cover.as_deref(), &processed_files);
+    let mut versions = None;
+    if let Some(version) = version {
+        let mut manifest = Versions::load(&book_dir.join("export").join("html"))?;
+        manifest.add(version);
+        context.insert("versions", &manifest.links(version));
+        versions = Some(manifest);
+        seo = seo.within(version);
     }
     
-    let theme_content = fs::read_to_string(&theme_path)?;
+    let mut theme = theme::Theme::load(book_dir, &config)?;
     
-    // Combine all HTML content
-    let combined_html: String = processed_files
+    if standalone {
+        // Inline the theme's own resources before the chapters go in, which
+        // have been handled already
+        const CONTENT_MARKER: &str = "<!-- papyrus:content -->";
+        let page = render_theme(&mut theme, book_dir, context, CONTENT_MARKER)?;
+        let resource_dirs: Vec<&Path> = [assets_dir.as_path()].into_iter().chain(theme.dir()).chain([book_dir]).collect();
+        let page = inline_page_resources(&page, &resource_dirs, search_index.as_deref())?;
+        let rendered = page.replacen(CONTENT_MARKER, &combine_chapters(&processed_files, &slugs), 1);
+        
+        let file_name = format!("{}-standalone.html", sanitize_filename(&config.title));
+        let output_path = export_dir.join(&file_name);
+        fs::write(&output_path, seo::insert_head_tags(&rendered, &seo.unpublished().book_tags(&file_name)))?;
+        return Ok(output_path);
+    }
+    
+    // Render every page before writing anything, so that a theme error
+    // leaves the previous book in place
+    let rendered_pages = if multi_page {
+        theme.ensure_template("page.html", || load_template(book_dir, "theme-html-page.html", THEME_HTML_PAGE_TEMPLATE))?;
+        theme.ensure_template("index.html", || load_template(book_dir, "theme-html-index.html", THEME_HTML_INDEX_TEMPLATE))?;
+        render_multi_page(&theme, context, &seo, &processed_files, &chapters, &toc)?
+    } else {
+        let rendered = render_theme(&mut theme, book_dir, context, &combine_chapters(&processed_files, &slugs))?;
+        // An edition's folder opens on the book itself
+        let file_name = match version {
+            Some(_) => "index.html".to_string(),
+            None => format!("{}.html", sanitize_filename(&config.title)),
+        };
+        let tags = seo.book_tags(&file_name);
+        vec![(file_name, seo::insert_head_tags(&rendered, &tags))]
+    };
+    
+    assets::copy_files(&copies, &export_dir, refresh_assets)?;
+    written.extend(copies.into_keys());
+    
+    let fonts_dir = assets_dir.join("fonts");
+    if fonts_dir.is_dir() {
+        if refresh_assets || !export_dir.join("fonts").is_dir() {
+            assets::copy_assets(&fonts_dir, &export_dir.join("fonts"))?;
+        }
+        written.extend(assets::relative_files(&fonts_dir)?.into_iter().map(|file| format!("fonts/{}", file)));
+    }
+    
+    if let Some(static_dir) = theme.static_dir() {
+        if refresh_assets || !export_dir.join("static").is_dir() {
+            assets::copy_assets(&static_dir, &export_dir.join("static"))?;
+        }
+        written.extend(assets::relative_files(&static_dir)?.into_iter().map(|file| format!("static/{}", file)));
+    }
+    
+    for (file_name, html) in &rendered_pages {
+        fs::write(export_dir.join(file_name), html)?;
+    }
+    let pages: Vec<String> = rendered_pages.into_iter().map(|(file_name, _)| file_name).collect();
+    seo.write_sitemap(&export_dir, &pages)?;
+    let output_path = export_dir.join(&pages[0]);
+    
+    match &versions {
+        Some(manifest) => {
+            let root = book_dir.join("export").join("html");
+            manifest.save(&root)?;
+            let sitemaps: Vec<String> = manifest.versions.iter().map(|v| format!("{}sitemap.xml", v.url)).collect();
+            seo.write_robots(&root, &sitemaps)?;
+        }
+        None => seo.write_robots(&export_dir, &["sitemap.xml".to_string()])?,
+    }
+    
+    if let Some(json) = &search_index {
+        fs::write(export_dir.join("searchindex.json"), json)?;
+        fs::write(export_dir.join("searchindex.js"), format!("window.searchIndex = {};\n", json))?;
+        fs::write(export_dir.join("search.js"), SEARCH_JS)?;
+        written.extend(["searchindex.json", "searchindex.js", "search.js"].map(String::from));
+    }
+    
+    fs::write(export_dir.join("reader.js"), READER_JS)?;
+    fs::write(export_dir.join("reader.css"), reader_css())?;
+    fs::write(export_dir.join("print.css"), print_css())?;
+    written.extend(["reader.js", "reader.css", "print.css"].map(String::from));
+    
+    // Copy CSS file if it exists
+    let css_path = assets_dir.join("style.css");
+    if css_path.exists() {
+        let css_content = fs::read_to_string(&css_path)?;
+        let css_output = export_dir.join("style.css");
+        fs::write(&css_output, css_content)?;
+        written.insert("style.css".to_string());
+    }
+    
+    // Last, so the service worker sees every file of the book
+    if config.html.as_ref().and_then(|h| h.pwa).unwrap_or(false) {
+        if multi_page {
+            pwa::write(&export_dir, &config, cover.as_deref().map(|cover| export_dir.join(cover)).as_deref(), &pages, written)?;
+        } else {
+            eprintln!("Warning: pwa only applies to the multi-page book (--multi-page)");
+        }
+    }
+    
+    cache.assets_current = true;
+    Ok(output_path)
+}
+
+/// Joins all chapters into the content of the single-page book.
+fn combine_chapters(files: &[MarkdownFile], slugs: &[String]) -> String {
+    files
         .iter()
-        .map(|file| {
+        .zip(slugs)
+        .map(|(file, slug)| {
             let title = file.frontmatter
                 .as_ref()
                 .and_then(|fm| fm.title.as_ref())
-                .map(|t| format!("<h1>{}</h1>", html_escape(t)))
-                .unwrap_or_default();
+                .map(|t| format!("<h1 id=\"{}\">{}</h1>", slug, html_escape(t)))
+                .unwrap_or_else(|| format!("<a id=\"{}\"></a>", slug));
             format!("{}\n{}", title, file.html)
         })
         .collect::<Vec<_>>()
-        .join("\n<hr>\n");
+        .join("\n<hr>\n")
+}
+
+/// Renders the theme's `html.html`, or `assets/theme-html.html`, with
+/// `context` and the given content.
+fn render_theme(theme: &mut theme::Theme, book_dir: &Path, mut context: Context, content: &str) -> Result<String> {
+    theme.ensure_template("html.html", || {
+        // Load theme template
+        let theme_path = book_dir.join("assets").join("theme-html.html");
+        if !theme_path.exists() {
+            return Err(PapyrusError::Asset(format!(
+                "Theme file not found: {}",
+                theme_path.display()
+            )));
+        }
+        Ok(fs::read_to_string(&theme_path)?)
+    })?;
+    
+    context.insert("content", content);
     
-    // Render template
-    let mut tera = Tera::default();
-    tera.add_raw_template("theme", &theme_content)?;
+    theme.render("html.html", &context)
+}
+
+/// The configured cover's URL: its place in `images/`, where it is added to
+/// `copies`, or a `data:` URI for standalone files.
+fn cover_url(book_dir: &Path, config: &Config, standalone: bool, copies: &mut BTreeMap<String, PathBuf>) -> Result<Option<String>> {
+    let cover_path = match &config.cover {
+        Some(cover) => book_dir.join("assets").join("images").join(cover),
+        None => return Ok(None),
+    };
+    if !cover_path.is_file() || !assets::media_type(&cover_path).is_some_and(|t| t.starts_with("image/")) {
+        return Ok(None);
+    }
     
-    let mut context = Context::new();
-    context.insert("title", &config.title);
-    context.insert("content", &combined_html);
+    if standalone {
+        return Ok(Some(assets::data_uri(&cover_path)?));
+    }
     
-    let rendered = tera.render("theme", &context)?;
+    let path = format!("images/{}", cover_path.file_name().unwrap_or_default().to_string_lossy());
+    copies.insert(path.clone(), cover_path);
+    Ok(Some(path))
+}
+
+/// Pulls the stylesheets, scripts and images a rendered page links to into
+/// the page itself. Theme references are resolved against the first of
+/// `resource_dirs` containing them, except for the generated search files.
+fn inline_page_resources(html: &str, resource_dirs: &[&Path], search_index: Option<&str>) -> Result<String> {
+    use regex::{Captures, Regex};
+    let link_re = Regex::new(r#"<link\b[^>]*\brel=["']stylesheet["'][^>]*>"#).unwrap();
+    let script_re = Regex::new(r#"<script\b[^>]*\bsrc=["']([^"']*)["'][^>]*>\s*</script>"#).unwrap();
+    let href_re = Regex::new(r#"\bhref=["']([^"']*)["']"#).unwrap();
+    let mut error = None;
     
-    // Write output
-    let export_dir = book_dir.join("export");
-    fs::create_dir_all(&export_dir)?;
+    let html = link_re.replace_all(html, |caps: &Captures| {
+        let original = caps[0].to_string();
+        match href_re.captures(&caps[0]).as_ref().map(|href| &href[1]) {
+            Some("reader.css") => return format!("<style>\n{}\n</style>", reader_css()),
+            Some("print.css") => return format!("<style>\n{}\n</style>", print_css()),
+            _ => {}
+        }
+        let css_path = match href_re.captures(&caps[0]).and_then(|href| find_resource(resource_dirs, &href[1])) {
+            Some(path) => path,
+            None => return original,
+        };
+        if error.is_some() {
+            return original;
+        }
+        
+        let css_dir = css_path.parent().unwrap_or(resource_dirs[0]);
+        match fs::read_to_string(&css_path).map_err(PapyrusError::from).and_then(|css| assets::inline_css_urls(&css, css_dir)) {
+            Ok(css) => format!("<style>\n{}\n</style>", css),
+            Err(e) => {
+                error = Some(e);
+                original
+            }
+        }
+    }).to_string();
     
-    let output_path = export_dir.join(format!("{}.html", sanitize_filename(&config.title)));
-    fs::write(&output_path, rendered)?;
+    let html = script_re.replace_all(&html, |caps: &Captures| {
+        let script = match (&caps[1], search_index) {
+            ("searchindex.js", Some(json)) => format!("window.searchIndex = {};", json),
+            ("search.js", _) => SEARCH_JS.to_string(),
+            ("reader.js", _) => READER_JS.to_string(),
+            (src, _) => match find_resource(resource_dirs, src).and_then(|path| fs::read_to_string(path).ok()) {
+                Some(script) => script,
+                None => return caps[0].to_string(),
+            },
+        };
+        format!("<script>\n{}\n</script>", script.replace("</script", "<\\/script"))
+    }).to_string();
     
-    // Copy CSS file if it exists
-    let css_path = book_dir.join("assets").join("style.css");
-    if css_path.exists() {
-        let css_content = fs::read_to_string(&css_path)?;
-        let css_output = export_dir.join("style.css");
-        fs::write(&css_output, css_content)?;
+    if let Some(e) = error {
+        return Err(e);
     }
     
-    Ok(())
+    // Images used by the theme itself
+    assets::inline_referenced_assets(&html, resource_dirs[0], &resource_dirs[1..])
+}
+
+fn find_resource(dirs: &[&Path], relative: &str) -> Option<PathBuf> {
+    dirs.iter().map(|dir| dir.join(relative)).find(|path| path.is_file())
+}
+
+/// Renders one page per chapter plus an `index.html`, returning their file
+/// names and HTML, the index first.
+///
+/// Pages are rendered with the theme's `page.html` and `index.html`, or
+/// `assets/theme-html-page.html` and `assets/theme-html-index.html`, falling
+/// back to the built-in templates.
+/// Chapter pages additionally get `chapter`, `chapter_title`, `content`,
+/// `previous` and `next`.
+fn render_multi_page(
+    theme: &theme::Theme,
+    context: Context,
+    seo: &Seo,
+    files: &[MarkdownFile],
+    chapters: &[theme::Chapter],
+    toc: &[theme::TocEntry],
+) -> Result<Vec<(String, String)>> {
+    let rendered = theme.render("index.html", &context)?;
+    let mut pages = vec![("index.html".to_string(), seo::insert_head_tags(&rendered, &seo.book_tags("index.html")))];
+    
+    for (index, (file, chapter)) in files.iter().zip(chapters).enumerate() {
+        let mut toc = toc.to_vec();
+        toc[index].current = true;
+        let previous = index.checked_sub(1).map(|prev| &toc[prev]);
+        let next = toc.get(index + 1);
+        
+        let content = match file.frontmatter.as_ref().and_then(|fm| fm.title.as_ref()) {
+            Some(title) => format!("<h1>{}</h1>\n{}", html_escape(title), file.html),
+            None => file.html.clone(),
+        };
+        
+        let mut context = context.clone();
+        context.insert("chapter", chapter);
+        context.insert("chapter_title", &chapter.title);
+        context.insert("content", &content);
+        context.insert("previous", &previous);
+        context.insert("next", &next);
+        context.insert("toc", &toc);
+        
+        let rendered = theme.render("page.html", &context)?;
+        let tags = seo.chapter_tags(file, &chapter_title(file, index), &chapter.url);
+        pages.push((chapter.url.clone(), seo::insert_head_tags(&rendered, &tags)));
+    }
+    
+    Ok(pages)
+}
+
+/// Reads a theme file from `assets/`, or returns the built-in default when the
+/// book doesn't have one.
+fn load_template(book_dir: &Path, filename: &str, default: &str) -> Result<String> {
+    let path = book_dir.join("assets").join(filename);
+    if path.exists() {
+        Ok(fs::read_to_string(&path)?)
+    } else {
+        Ok(default.to_string())
+    }
+}
+
+/// One searchable section of the book: a chapter's text up to its first
+/// heading, or a heading and the text that follows it.
+#[derive(Serialize)]
+struct SearchEntry {
+    chapter: String,
+    heading: String,
+    url: String,
+    text: String,
+}
+
+/// Builds the search index as JSON. It is written as `searchindex.json` and,
+/// for books opened from `file://`, as the script `searchindex.js`.
+fn build_search_index(files: &[MarkdownFile], slugs: &[String], multi_page: bool) -> Result<String> {
+    use regex::Regex;
+    let heading_re = Regex::new(r#"(?s)<h[1-6] id="([^"]*)">(.*?)</h[1-6]>"#).unwrap();
+    
+    let mut entries = Vec::new();
+    for (index, (file, slug)) in files.iter().zip(slugs).enumerate() {
+        let chapter = chapter_title(file, index);
+        let page = if multi_page { format!("{}.html", slug) } else { String::new() };
+        
+        let mut push = |heading: &str, anchor: &str, html: &str| {
+            let text = html_to_text(html);
+            if heading.is_empty() && text.is_empty() {
+                return;
+            }
+            entries.push(SearchEntry {
+                chapter: chapter.clone(),
+                heading: if heading.is_empty() { chapter.clone() } else { heading.to_string() },
+                url: if anchor.is_empty() { page.clone() } else { format!("{}#{}", page, anchor) },
+                text,
+            });
+        };
+        
+        let mut heading = String::new();
+        // Chapters start at the top of their page, or at their anchor on a single page
+        let mut anchor = if multi_page { String::new() } else { slug.clone() };
+        let mut last = 0;
+        for caps in heading_re.captures_iter(&file.html) {
+            let whole = caps.get(0).unwrap();
+            push(&heading, &anchor, &file.html[last..whole.start()]);
+            heading = html_to_text(&caps[2]);
+            anchor = caps[1].to_string();
+            last = whole.end();
+        }
+        push(&heading, &anchor, &file.html[last..]);
+    }
+    
+    serde_json::to_string(&entries)
+        .map_err(|e| PapyrusError::Asset(format!("Failed to serialize search index: {}", e)))
 }
 
 pub fn highlight_code_blocks(html: &str) -> String {
     let ps = SyntaxSet::load_defaults_newlines();
-    let ts = ThemeSet::load_defaults();
-    let theme = &ts.themes["base16-ocean.dark"];
     
-    // Simple regex-based approach to find and highlight code blocks
+    // Simple regex-based approach to find and highlight code blocks. Code is
+    // marked up with classes, so its colours come from `highlight_css`.
     use regex::Regex;
     let code_block_re = Regex::new(r#"(?s)<pre><code(?: class="language-(\w+)")?>([^<]+)</code></pre>"#).unwrap();
     
@@ -96,21 +513,84 @@ pub fn highlight_code_blocks(html: &str) -> String {
         let code = caps.get(2).map(|m| m.as_str()).unwrap_or("");
         
         if let Some(syntax) = ps.find_syntax_by_token(language) {
-            let mut highlighter = HighlightLines::new(syntax, theme);
-            let mut highlighted = String::new();
-            
-            for line in LinesWithEndings::from(code) {
-                let ranges: Vec<(Style, &str)> = highlighter.highlight_line(line, &ps).unwrap();
-                highlighted.push_str(&as_24_bit_terminal_escaped(&ranges[..], false));
+            let code = html_unescape(code);
+            let mut generator = ClassedHTMLGenerator::new_with_class_style(syntax, &ps, HIGHLIGHT_CLASS_STYLE);
+            for line in LinesWithEndings::from(&code) {
+                generator.parse_html_for_line_which_includes_newline(line).unwrap();
             }
             
-            format!("<pre><code class=\"language-{}\">{}</code></pre>", language, code)
+            format!("<pre class=\"hl-code\"><code class=\"language-{}\">{}</code></pre>", language, generator.finalize())
         } else {
-            format!("<pre><code>{}</code></pre>", html_escape(code))
+            // Already escaped by the Markdown renderer
+            caps[0].to_string()
         }
     }).to_string()
 }
 
+/// CSS colouring highlighted code in the given reader colour scheme (`light`,
+/// `dark` or `sepia`). Every selector is prefixed with `scope`, if any.
+pub fn highlight_css(scheme: &str, scope: &str) -> String {
+    use regex::Regex;
+    let ts = ThemeSet::load_defaults();
+    let theme_name = HIGHLIGHT_THEMES
+        .iter()
+        .find(|(name, _)| *name == scheme)
+        .map_or(HIGHLIGHT_THEMES[0].1, |(_, theme)| theme);
+    let css = css_for_theme_with_class_style(&ts.themes[theme_name], HIGHLIGHT_CLASS_STYLE).unwrap();
+    
+    let comment_re = Regex::new(r"(?s)/\*.*?\*/").unwrap();
+    let rule_re = Regex::new(r"([^{}]+)\{([^{}]*)\}").unwrap();
+    let css = comment_re.replace_all(&css, "");
+    rule_re
+        .captures_iter(&css)
+        .map(|rule| {
+            let selectors: Vec<String> = rule[1]
+                .split(',')
+                .map(|selector| match scope {
+                    "" => selector.trim().to_string(),
+                    scope => format!("{} {}", scope, selector.trim()),
+                })
+                .collect();
+            format!("{} {{{}}}", selectors.join(", "), rule[2].trim_end_matches('\n'))
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The stylesheet behind the reader settings: colour schemes, line widths and
+/// code colours for each scheme.
+fn reader_css() -> String {
+    let mut css = READER_CSS.to_string();
+    for (scheme, _) in HIGHLIGHT_THEMES {
+        let scope = match *scheme {
+            // Also the default, before reader.js has run
+            "light" => ":root:not([data-theme=\"dark\"]):not([data-theme=\"sepia\"])".to_string(),
+            scheme => format!(":root[data-theme=\"{}\"]", scheme),
+        };
+        css.push_str(&format!("\n/* {} */\n{}\n", scheme, highlight_css(scheme, &scope)));
+    }
+    css
+}
+
+/// The print stylesheet: the PDF page rules, plus print rules for the HTML
+/// themes with light code colours whatever the reader's colour scheme.
+fn print_css() -> String {
+    format!(
+        "{}\n{}\n@media print {{\n{}\n}}\n",
+        PAGE_CSS,
+        PRINT_CSS,
+        highlight_css("light", "html:root[data-theme]")
+    )
+}
+
+fn html_unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
 fn html_escape(s: &str) -> String {
     s.replace("&", "&amp;")
         .replace("<", "&lt;")
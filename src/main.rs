@@ -6,7 +6,15 @@ mod config;
 mod markdown;
 mod html;
 mod epub;
+mod epub_check;
 mod pdf;
+mod preprocess;
+mod pwa;
+mod seo;
+mod serve;
+mod theme;
+mod versions;
+mod watch;
 mod assets;
 mod error;
 
@@ -20,18 +28,29 @@ async fn main() -> Result<()> {
         cli::Commands::Init { path } => {
             cli::init_command(path).await?;
         }
-        cli::Commands::Pdf { theme, content, book_dir } => {
-            cli::pdf_command(theme, content, book_dir).await?;
+        cli::Commands::Pdf { theme, content, book_dir, watch } => {
+            cli::pdf_command(theme, content, book_dir, watch).await?;
         }
-        cli::Commands::Epub { content, book_dir } => {
-            cli::epub_command(content, book_dir).await?;
+        cli::Commands::Epub { content, book_dir, watch, check } => {
+            cli::epub_command(content, book_dir, watch, check).await?;
         }
-        cli::Commands::Html { content, book_dir } => {
-            cli::html_command(content, book_dir).await?;
+        cli::Commands::Html { content, book_dir, multi_page, standalone, watch } => {
+            cli::html_command(content, book_dir, multi_page, standalone, watch).await?;
+        }
+        cli::Commands::Serve { content, book_dir, multi_page, port } => {
+            cli::serve_command(content, book_dir, multi_page, port).await?;
         }
         cli::Commands::Sample { theme } => {
             cli::sample_command(theme).await?;
         }
+        cli::Commands::Theme { command } => match command {
+            cli::ThemeCommands::List { book_dir } => {
+                cli::theme_list_command(book_dir).await?;
+            }
+            cli::ThemeCommands::Eject { name, book_dir } => {
+                cli::theme_eject_command(name, book_dir).await?;
+            }
+        },
     }
     
     Ok(())
@@ -1,9 +1,39 @@
-use pulldown_cmark::{Parser, Options, html};
+use pulldown_cmark::{Parser, Options, Event, Tag, TagEnd, CodeBlockKind, CowStr, TextMergeStream, html};
 use gray_matter::Matter;
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use std::path::{Path, PathBuf};
 use crate::error::{PapyrusError, Result};
+use crate::config::Config;
 use std::fs;
+use tera::{Tera, Context};
+
+/// The output a book is being rendered for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Html,
+    Epub,
+    Pdf,
+}
+
+impl OutputFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OutputFormat::Html => "html",
+            OutputFormat::Epub => "epub",
+            OutputFormat::Pdf => "pdf",
+        }
+    }
+    
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "html" => Some(OutputFormat::Html),
+            "epub" => Some(OutputFormat::Epub),
+            "pdf" => Some(OutputFormat::Pdf),
+            _ => None,
+        }
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FrontMatter {
@@ -16,16 +46,16 @@ pub struct FrontMatter {
 
 #[derive(Debug, Clone)]
 pub struct MarkdownFile {
-    #[allow(dead_code)]
     pub path: PathBuf,
     pub frontmatter: Option<FrontMatter>,
-    #[allow(dead_code)]
     pub content: String,
     pub html: String,
 }
 
 impl MarkdownFile {
-    pub fn parse<P: AsRef<Path>>(path: P) -> Result<Self> {
+    /// Reads a chapter and prepares its Markdown for `format`, leaving `html`
+    /// empty until [`MarkdownFile::render`] is called.
+    pub fn load<P: AsRef<Path>>(path: P, variables: &Context, format: OutputFormat) -> Result<Self> {
         let path = path.as_ref();
         let content = fs::read_to_string(path)?;
         
@@ -33,34 +63,60 @@ impl MarkdownFile {
         let matter = Matter::<gray_matter::engine::YAML>::new();
         let parsed = matter.parse(&content);
         
-        let frontmatter = if parsed.data.is_some() {
-            let fm: FrontMatter = parsed.data.unwrap().deserialize()
+        let frontmatter = if let Some(data) = parsed.data {
+            let fm: FrontMatter = data.deserialize()
                 .map_err(|e| PapyrusError::Markdown(format!("Failed to parse frontmatter: {}", e)))?;
             Some(fm)
         } else {
             None
         };
         
-        let markdown_content = parsed.content;
+        // Substitute `{{ book.title }}` and friends before anything else
+        let markdown_content = substitute_variables(&parsed.content, variables)
+            .map_err(|e| PapyrusError::Markdown(format!("{}: {}", path.display(), e)))?;
         
-        // Parse markdown to HTML
-        let mut options = Options::empty();
-        options.insert(Options::ENABLE_STRIKETHROUGH);
-        options.insert(Options::ENABLE_TABLES);
-        options.insert(Options::ENABLE_FOOTNOTES);
-        options.insert(Options::ENABLE_TASKLISTS);
+        // Check raw blocks while lines still match the file; conditional
+        // blocks may drop some of them below
+        let first_line = body_first_line(&content, &parsed.content);
+        passthrough_raw_blocks(&markdown_content, Parser::new_ext(&markdown_content, markdown_options()).into_offset_iter(), format, first_line)
+            .map_err(|e| PapyrusError::Markdown(format!("{}: {}", path.display(), e)))?;
         
-        let parser = Parser::new_ext(&markdown_content, options);
-        let mut html_output = String::new();
-        html::push_html(&mut html_output, parser);
+        // Drop conditional blocks that don't target this output
+        let markdown_content = filter_conditional_blocks(&markdown_content, format, first_line)
+            .map_err(|e| PapyrusError::Markdown(format!("{}: {}", path.display(), e)))?;
         
         Ok(MarkdownFile {
             path: path.to_path_buf(),
             frontmatter,
             content: markdown_content,
-            html: html_output,
+            html: String::new(),
         })
     }
+    
+    /// Renders `content` to HTML for `format`.
+    pub fn render(&mut self, format: OutputFormat) -> Result<()> {
+        let parser = Parser::new_ext(&self.content, markdown_options());
+        let events = passthrough_raw_blocks(&self.content, parser.into_offset_iter(), format, 1)
+            .map_err(|e| PapyrusError::Markdown(format!("{}: {}", self.path.display(), e)))?;
+        let events = outside_code(TextMergeStream::new(events.into_iter()), inline_extensions);
+        let mut html_output = String::new();
+        html::push_html(&mut html_output, events);
+        
+        self.html = html_output;
+        Ok(())
+    }
+}
+
+fn markdown_options() -> Options {
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+    options.insert(Options::ENABLE_TABLES);
+    options.insert(Options::ENABLE_FOOTNOTES);
+    options.insert(Options::ENABLE_TASKLISTS);
+    options.insert(Options::ENABLE_DEFINITION_LIST);
+    options.insert(Options::ENABLE_SUPERSCRIPT);
+    options.insert(Options::ENABLE_SUBSCRIPT);
+    options
 }
 
 pub fn collect_markdown_files<P: AsRef<Path>>(content_dir: P, md_file_list: Option<&[String]>) -> Result<Vec<PathBuf>> {
@@ -101,8 +157,665 @@ pub fn collect_markdown_files<P: AsRef<Path>>(content_dir: P, md_file_list: Opti
     Ok(files)
 }
 
-pub fn parse_markdown_files(files: &[PathBuf]) -> Result<Vec<MarkdownFile>> {
-    files.iter()
-        .map(MarkdownFile::parse)
+pub fn parse_markdown_files(book_dir: &Path, files: &[PathBuf], config: &Config, format: OutputFormat) -> Result<Vec<MarkdownFile>> {
+    let mut chapters = load_markdown_files(book_dir, files, config, format)?;
+    for chapter in &mut chapters {
+        chapter.render(format)?;
+    }
+    
+    Ok(chapters)
+}
+
+/// Reads the chapters and runs the preprocessors, leaving the rendering to
+/// the caller.
+pub fn load_markdown_files(book_dir: &Path, files: &[PathBuf], config: &Config, format: OutputFormat) -> Result<Vec<MarkdownFile>> {
+    let variables = variables_context(config);
+    let loaded = files.iter()
+        .map(|file| MarkdownFile::load(file, &variables, format))
+        .collect::<Result<Vec<_>>>()?;
+    
+    crate::preprocess::run_preprocessors(book_dir, config, format, loaded)
+}
+
+/// Builds the Tera context chapters are rendered with: the whole config as
+/// `book`, plus every entry of `[variables]` at the top level.
+pub fn variables_context(config: &Config) -> Context {
+    let mut context = Context::new();
+    if let Some(variables) = &config.variables {
+        for (name, value) in variables {
+            context.insert(name.as_str(), value);
+        }
+    }
+    context.insert("book", config);
+    context
+}
+
+/// The line of the file the body gray_matter returns starts on. gray_matter
+/// drops the frontmatter and any blank lines after it, and rejoins the
+/// remaining lines without the final newline.
+fn body_first_line(content: &str, body: &str) -> usize {
+    if body.is_empty() {
+        return 1;
+    }
+    content.lines().count().saturating_sub(body.split('\n').count()) + 1
+}
+
+/// Renders chapter Markdown through Tera.
+///
+/// Code spans and code blocks are left exactly as written, so samples full of
+/// `{{`, `{%` or `${#...}` don't need escaping; raw blocks are still rendered.
+/// Elsewhere `\{{`, `\{%` and `\{#` produce the literal delimiters, and
+/// `{% raw %}` works as well.
+pub fn substitute_variables(content: &str, variables: &Context) -> std::result::Result<String, String> {
+    if !content.contains("{{") && !content.contains("{%") && !content.contains("{#") {
+        return Ok(content.to_string());
+    }
+    
+    // Swap code for placeholders Tera passes through, and put it back after
+    let mut code = Vec::new();
+    let mut template = String::with_capacity(content.len());
+    let mut last = 0;
+    for range in code_ranges(content) {
+        template.push_str(&escape_delimiters(&content[last..range.start]));
+        template.push_str(&format!("\u{E000}{}\u{E001}", code.len()));
+        code.push(&content[range.clone()]);
+        last = range.end;
+    }
+    template.push_str(&escape_delimiters(&content[last..]));
+    
+    let rendered = Tera::one_off(&template, variables, false).map_err(|e| {
+        // Tera nests the useful message in the error's sources
+        let mut message = e.to_string();
+        let mut source = std::error::Error::source(&e);
+        while let Some(err) = source {
+            message.push_str(&format!(": {}", err));
+            source = err.source();
+        }
+        message
+    })?;
+    
+    if code.is_empty() {
+        return Ok(rendered);
+    }
+    let placeholder_re = regex::Regex::new("\u{E000}(\\d+)\u{E001}").unwrap();
+    Ok(placeholder_re
+        .replace_all(&rendered, |caps: &regex::Captures| {
+            caps[1].parse::<usize>().ok().and_then(|index| code.get(index)).copied().unwrap_or_default()
+        })
+        .to_string())
+}
+
+fn escape_delimiters(text: &str) -> String {
+    text.replace("\\{{", "{{ \"{{\" }}")
+        .replace("\\{%", "{{ \"{%\" }}")
+        .replace("\\{#", "{{ \"{#\" }}")
+}
+
+/// Byte ranges of the code spans and code blocks in `content`, except raw
+/// blocks, in document order.
+fn code_ranges(content: &str) -> Vec<std::ops::Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut in_block = false;
+    for (event, range) in Parser::new(content).into_offset_iter() {
+        match event {
+            Event::Start(Tag::CodeBlock(kind)) => {
+                in_block = true;
+                let raw = matches!(&kind, CodeBlockKind::Fenced(info) if info.trim().starts_with("{="));
+                if !raw {
+                    ranges.push(range);
+                }
+            }
+            Event::End(TagEnd::CodeBlock) => in_block = false,
+            Event::Code(_) if !in_block => ranges.push(range),
+            _ => {}
+        }
+    }
+    ranges
+}
+
+/// Handles raw blocks fenced with ```` ```{=html} ````, ```` ```{=epub} ```` or
+/// ```` ```{=pdf} ````.
+///
+/// The block body is emitted untouched for the matching output and dropped
+/// for the others. EPUB content is checked for XHTML well-formedness, since
+/// a single stray tag makes the whole book invalid. Errors count lines from
+/// `first_line`, the line of the file `source` starts on.
+fn passthrough_raw_blocks<'a>(
+    source: &str,
+    events: impl Iterator<Item = (Event<'a>, std::ops::Range<usize>)>,
+    format: OutputFormat,
+    first_line: usize,
+) -> std::result::Result<Vec<Event<'a>>, String> {
+    let mut output = Vec::new();
+    // Target format, raw text and starting line of the block being collected
+    let mut raw: Option<(OutputFormat, String, usize)> = None;
+    
+    for (event, range) in events {
+        if let Some((target, body, line)) = &mut raw {
+            match event {
+                Event::Text(text) => body.push_str(&text),
+                Event::End(TagEnd::CodeBlock) => {
+                    if *target == format {
+                        if format == OutputFormat::Epub {
+                            check_xhtml_fragment(body).map_err(|(row, message)| format!(
+                                "line {}: raw epub block is not well-formed XHTML: {}",
+                                *line + row,
+                                message
+                            ))?;
+                        }
+                        output.push(Event::Html(CowStr::from(std::mem::take(body))));
+                    }
+                    raw = None;
+                }
+                _ => {}
+            }
+            continue;
+        }
+        
+        if let Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info))) = &event {
+            let info = info.trim();
+            if let Some(name) = info.strip_prefix("{=").and_then(|rest| rest.strip_suffix('}')) {
+                let line = first_line + source[..range.start].matches('\n').count();
+                let target = OutputFormat::from_name(&name.trim().to_lowercase()).ok_or_else(|| format!(
+                    "line {}: unknown output format `{}` in raw block (expected html, epub or pdf)",
+                    line,
+                    name.trim()
+                ))?;
+                raw = Some((target, String::new(), line));
+                continue;
+            }
+        }
+        
+        output.push(event);
+    }
+    
+    Ok(output)
+}
+
+/// Parses an XHTML fragment, returning the 1-based line and message of the
+/// first error.
+fn check_xhtml_fragment(fragment: &str) -> std::result::Result<(), (usize, String)> {
+    let document = format!(
+        "<div xmlns=\"http://www.w3.org/1999/xhtml\" xmlns:epub=\"http://www.idpf.org/2007/ops\">{}</div>",
+        fragment
+    );
+    roxmltree::Document::parse(&document).map(|_| ()).map_err(|e| {
+        let message = e.to_string();
+        // Drop roxmltree's own position, it points into the wrapper
+        let message = message
+            .rsplit_once(" at ")
+            .map(|(message, _)| message.to_string())
+            .unwrap_or(message);
+        (e.pos().row as usize, message)
+    })
+}
+
+/// Applies `transform` to every event except those inside code blocks and
+/// image alt text, which are passed through verbatim.
+fn outside_code<'a, I>(events: I, transform: fn(Event<'a>) -> Vec<Event<'a>>) -> impl Iterator<Item = Event<'a>>
+where
+    I: Iterator<Item = Event<'a>>,
+{
+    let mut verbatim = 0usize;
+    events.flat_map(move |event| {
+        match &event {
+            Event::Start(Tag::CodeBlock(_) | Tag::Image { .. }) => verbatim += 1,
+            Event::End(TagEnd::CodeBlock | TagEnd::Image) => verbatim = verbatim.saturating_sub(1),
+            _ => {}
+        }
+        if verbatim > 0 {
+            vec![event]
+        } else {
+            transform(event)
+        }
+    })
+}
+
+/// Renders `==mark==`, `H~2~O` and `x^2^` found inside a text event.
+///
+/// pulldown-cmark only recognises `~sub~` and `^sup^` between word boundaries,
+/// so the intraword forms are picked up here. Code spans arrive as other
+/// events; code blocks and alt text are skipped by [`outside_code`].
+fn inline_extensions(event: Event<'_>) -> Vec<Event<'_>> {
+    use regex::Regex;
+    use std::sync::OnceLock;
+    static INLINE_RE: OnceLock<Regex> = OnceLock::new();
+    
+    let text = match event {
+        Event::Text(text) if text.contains("==") || text.contains('~') || text.contains('^') => text,
+        other => return vec![other],
+    };
+    
+    let inline_re = INLINE_RE.get_or_init(|| {
+        Regex::new(r"==(?P<mark>[^=\s](?:[^=]*[^=\s])?)==|~(?P<sub>[^~\s]+)~|\^(?P<sup>[^\^\s]+)\^").unwrap()
+    });
+    let mut events = Vec::new();
+    let mut last = 0;
+    for caps in inline_re.captures_iter(&text) {
+        let whole = caps.get(0).unwrap();
+        let (tag, inner) = if let Some(inner) = caps.name("mark") {
+            ("mark", inner)
+        } else if let Some(inner) = caps.name("sub") {
+            ("sub", inner)
+        } else {
+            ("sup", caps.name("sup").unwrap())
+        };
+        
+        if whole.start() > last {
+            events.push(Event::Text(CowStr::from(text[last..whole.start()].to_string())));
+        }
+        events.push(Event::InlineHtml(CowStr::from(format!("<{}>", tag))));
+        events.push(Event::Text(CowStr::from(inner.as_str().to_string())));
+        events.push(Event::InlineHtml(CowStr::from(format!("</{}>", tag))));
+        last = whole.end();
+    }
+    
+    if last == 0 {
+        return vec![Event::Text(text)];
+    }
+    if last < text.len() {
+        events.push(Event::Text(CowStr::from(text[last..].to_string())));
+    }
+    events
+}
+
+struct ConditionalFrame {
+    visible: bool,
+    line: usize,
+    // Fences we don't handle ourselves (e.g. `::: note`) are kept as-is
+    passthrough: bool,
+}
+
+/// Evaluates `::: {only=pdf}` ... `:::` blocks for the given output format.
+///
+/// Several formats can be listed with commas (`::: {only=html,epub}`). Blocks
+/// may be nested, and fences inside fenced code blocks are left untouched.
+/// Errors count lines from `first_line`, the line of the file `content`
+/// starts on.
+pub fn filter_conditional_blocks(content: &str, format: OutputFormat, first_line: usize) -> std::result::Result<String, String> {
+    use regex::Regex;
+    let open_re = Regex::new(r#"^\s*:{3,}\s*\{\s*only\s*=\s*"?([A-Za-z0-9_,\s]+?)"?\s*\}\s*$"#).unwrap();
+    let other_open_re = Regex::new(r"^\s*:{3,}\s*\S").unwrap();
+    let close_re = Regex::new(r"^\s*:{3,}\s*$").unwrap();
+    
+    let mut output = String::with_capacity(content.len());
+    let mut stack: Vec<ConditionalFrame> = Vec::new();
+    let mut code_fence: Option<String> = None;
+    
+    for (line_no, line) in content.split_inclusive('\n').enumerate() {
+        let visible = stack.iter().all(|frame| frame.visible);
+        let trimmed = line.trim_start();
+        
+        // Inside a fenced code block nothing is interpreted
+        if let Some(fence) = &code_fence {
+            let candidate = trimmed.trim_end();
+            if candidate.starts_with(fence.as_str()) && candidate.chars().all(|c| fence.starts_with(c)) {
+                code_fence = None;
+            }
+            if visible {
+                output.push_str(line);
+            }
+            continue;
+        }
+        
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            let marker = trimmed.chars().next().unwrap();
+            let fence: String = trimmed.chars().take_while(|&c| c == marker).collect();
+            code_fence = Some(fence);
+            if visible {
+                output.push_str(line);
+            }
+            continue;
+        }
+        
+        if let Some(caps) = open_re.captures(line.trim_end()) {
+            let mut matches = false;
+            for name in caps[1].split(',').map(str::trim).filter(|n| !n.is_empty()) {
+                let target = OutputFormat::from_name(&name.to_lowercase()).ok_or_else(|| format!(
+                    "line {}: unknown output format `{}` in conditional block (expected html, epub or pdf)",
+                    first_line + line_no,
+                    name
+                ))?;
+                matches |= target == format;
+            }
+            stack.push(ConditionalFrame { visible: matches, line: first_line + line_no, passthrough: false });
+            continue;
+        }
+        
+        if close_re.is_match(line.trim_end()) {
+            match stack.pop() {
+                Some(frame) if frame.passthrough => {
+                    if visible {
+                        output.push_str(line);
+                    }
+                }
+                Some(_) => {}
+                None => output.push_str(line),
+            }
+            continue;
+        }
+        
+        if other_open_re.is_match(line) {
+            stack.push(ConditionalFrame { visible: true, line: first_line + line_no, passthrough: true });
+        }
+        
+        if visible {
+            output.push_str(line);
+        }
+    }
+    
+    if let Some(frame) = stack.iter().rev().find(|frame| !frame.passthrough) {
+        return Err(format!("line {}: unclosed conditional block (missing `:::`)", frame.line));
+    }
+    
+    Ok(output)
+}
+
+/// The chapter's frontmatter title, or `Chapter N`.
+pub fn chapter_title(file: &MarkdownFile, index: usize) -> String {
+    file.frontmatter
+        .as_ref()
+        .and_then(|fm| fm.title.clone())
+        .unwrap_or_else(|| format!("Chapter {}", index + 1))
+}
+
+/// Derives a URL-friendly, unique slug for every chapter from its file name,
+/// dropping ordering prefixes: `01-getting-started.md` becomes `getting-started`.
+pub fn chapter_slugs(files: &[MarkdownFile]) -> Vec<String> {
+    let mut seen = HashSet::new();
+    // The index page is generated, so no chapter may take its name
+    seen.insert("index".to_string());
+    
+    files
+        .iter()
+        .enumerate()
+        .map(|(index, file)| {
+            let stem = file.path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .unwrap_or_default();
+            let stem = stem.trim_start_matches(|c: char| c.is_ascii_digit() || c == '-' || c == '_' || c == '.');
+            
+            let slug = slugify(stem);
+            let slug = if slug.is_empty() { format!("chapter-{}", index + 1) } else { slug };
+            unique_slug(&slug, &mut seen)
+        })
+        .collect()
+}
+
+/// Lowercases `text` and joins its alphanumeric runs with dashes.
+pub fn slugify(text: &str) -> String {
+    let mut slug = String::new();
+    for c in text.chars().flat_map(char::to_lowercase) {
+        if c.is_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_end_matches('-').to_string()
+}
+
+/// Returns `slug`, or `slug-2`, `slug-3`... if it was taken, and records it.
+pub fn unique_slug(slug: &str, seen: &mut HashSet<String>) -> String {
+    let mut unique = slug.to_string();
+    let mut counter = 2;
+    while !seen.insert(unique.clone()) {
+        unique = format!("{}-{}", slug, counter);
+        counter += 1;
+    }
+    unique
+}
+
+/// Gives every heading in the chapters an `id` derived from its text, so
+/// sections can be linked to.
+///
+/// On a single page ids must be unique across the whole book and must not
+/// clash with the chapter anchors; on separate pages only within a chapter.
+pub fn add_heading_ids(files: Vec<MarkdownFile>, slugs: &[String], multi_page: bool) -> Vec<MarkdownFile> {
+    use regex::Regex;
+    let heading_re = Regex::new(r"(?s)<h([1-6])>(.*?)</h([1-6])>").unwrap();
+    
+    let mut used: HashSet<String> = if multi_page {
+        HashSet::new()
+    } else {
+        slugs.iter().cloned().collect()
+    };
+    
+    files
+        .into_iter()
+        .map(|mut file| {
+            if multi_page {
+                used.clear();
+            }
+            file.html = heading_re.replace_all(&file.html, |caps: &regex::Captures| {
+                let base = slugify(&html_to_text(&caps[2]));
+                let base = if base.is_empty() { "section".to_string() } else { base };
+                let id = unique_slug(&base, &mut used);
+                format!("<h{} id=\"{}\">{}</h{}>", &caps[1], id, &caps[2], &caps[3])
+            }).to_string();
+            file
+        })
         .collect()
 }
+
+/// A heading found in a chapter's rendered HTML.
+#[derive(Debug, Clone, Serialize)]
+pub struct Heading {
+    pub level: u8,
+    /// Plain text of the heading
+    pub title: String,
+    pub id: String,
+}
+
+/// Lists the headings that [`add_heading_ids`] gave an `id`.
+pub fn extract_headings(html: &str) -> Vec<Heading> {
+    use regex::Regex;
+    let heading_re = Regex::new(r#"(?s)<h([1-6]) id="([^"]*)">(.*?)</h[1-6]>"#).unwrap();
+    heading_re
+        .captures_iter(html)
+        .map(|caps| Heading {
+            level: caps[1].parse().unwrap_or(1),
+            title: html_to_text(&caps[3]),
+            id: caps[2].to_string(),
+        })
+        .collect()
+}
+
+/// Strips tags and decodes the entities pulldown-cmark produces, collapsing
+/// whitespace.
+pub fn html_to_text(html: &str) -> String {
+    use regex::Regex;
+    use std::sync::OnceLock;
+    static TAG_RE: OnceLock<Regex> = OnceLock::new();
+    let tag_re = TAG_RE.get_or_init(|| Regex::new(r"(?s)<[^>]*>").unwrap());
+    
+    let text = tag_re.replace_all(html, " ");
+    let text = text
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#x27;", "'")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&");
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render_as(markdown: &str, format: OutputFormat) -> Result<String> {
+        let mut file = MarkdownFile {
+            path: PathBuf::from("chapter.md"),
+            frontmatter: None,
+            content: markdown.to_string(),
+            html: String::new(),
+        };
+        file.render(format)?;
+        Ok(file.html)
+    }
+
+    fn render(markdown: &str) -> String {
+        render_as(markdown, OutputFormat::Html).unwrap()
+    }
+
+    #[test]
+    fn raw_blocks_are_kept_for_their_format_only() {
+        let markdown = "Text\n\n```{=html}\n<aside>Web</aside>\n```\n\n```{=epub}\n<aside epub:type=\"note\">Book</aside>\n```\n\n```{=pdf}\n<div class=\"page-break\"></div>\n```\n";
+        assert_eq!(render_as(markdown, OutputFormat::Html).unwrap(), "<p>Text</p>\n<aside>Web</aside>\n");
+        assert_eq!(render_as(markdown, OutputFormat::Pdf).unwrap(), "<p>Text</p>\n<div class=\"page-break\"></div>\n");
+        assert_eq!(
+            render_as(markdown, OutputFormat::Epub).unwrap(),
+            "<p>Text</p>\n<aside epub:type=\"note\">Book</aside>\n"
+        );
+    }
+
+    #[test]
+    fn raw_epub_blocks_must_be_xhtml() {
+        let error = render_as("Text\n\n```{=epub}\n<p>One<br></p>\n```\n", OutputFormat::Epub).unwrap_err().to_string();
+        assert!(error.contains("line 4: raw epub block is not well-formed XHTML"), "{}", error);
+        // Other formats don't care
+        assert!(render_as("```{=epub}\n<p>One<br></p>\n```\n", OutputFormat::Html).is_ok());
+        assert!(render_as("```{=epub}\n<p>One<br/></p>\n```\n", OutputFormat::Epub).is_ok());
+    }
+
+    #[test]
+    fn raw_blocks_need_a_known_format() {
+        let error = render_as("```{=mobi}\nx\n```\n", OutputFormat::Html).unwrap_err().to_string();
+        assert!(error.contains("line 1: unknown output format `mobi` in raw block"), "{}", error);
+    }
+
+    #[test]
+    fn renders_marks_and_intraword_sub_and_superscript() {
+        assert_eq!(
+            render("H~2~O and x^2^ are ==important==.\n"),
+            "<p>H<sub>2</sub>O and x<sup>2</sup> are <mark>important</mark>.</p>\n"
+        );
+    }
+
+    #[test]
+    fn leaves_code_untouched() {
+        let html = render("```c\nint x = a^b^c;\nif (p==q==r) {}\n```\n\n    y = H~2~O;\n\nUse `a^b^c` here.\n");
+        assert!(!html.contains("<sup>"), "{}", html);
+        assert!(!html.contains("<mark>"), "{}", html);
+        assert!(!html.contains("<sub>"), "{}", html);
+        assert!(html.contains("int x = a^b^c;\nif (p==q==r) {}"), "{}", html);
+        assert!(html.contains("y = H~2~O;"), "{}", html);
+        assert!(html.contains("<code>a^b^c</code>"), "{}", html);
+    }
+
+    #[test]
+    fn leaves_image_alt_text_untouched() {
+        let html = render("![CO~2~ levels ==rising==](chart.png)\n");
+        assert_eq!(html, "<p><img src=\"chart.png\" alt=\"CO~2~ levels ==rising==\" /></p>\n");
+    }
+
+    fn variables() -> Context {
+        let mut context = Context::new();
+        context.insert("product", "Papyrus");
+        context.insert("book", &serde_json::json!({ "version": "2.1" }));
+        context
+    }
+
+    #[test]
+    fn substitutes_variables() {
+        assert_eq!(
+            substitute_variables("About {{ product }} {{ book.version }}.\n", &variables()).unwrap(),
+            "About Papyrus 2.1.\n"
+        );
+        assert!(substitute_variables("{{ missing }}\n", &variables()).is_err());
+    }
+
+    #[test]
+    fn escaped_delimiters_stay_literal() {
+        assert_eq!(
+            substitute_variables("\\{{ product }} and \\{% raw %} and \\{# note #}\n", &variables()).unwrap(),
+            "{{ product }} and {% raw %} and {# note #}\n"
+        );
+        assert_eq!(
+            substitute_variables("{% raw %}{{ product }}{% endraw %} {{ product }}\n", &variables()).unwrap(),
+            "{{ product }} Papyrus\n"
+        );
+    }
+
+    #[test]
+    fn code_is_left_untouched() {
+        let content = "{{ product }}\n\n```bash\necho \"${#arr[@]}\" {{ product }}\n```\n\n    println!(\"{{}}\", x);\n\nRun `{% raw %}` or `{{ product }}`.\n";
+        assert_eq!(
+            substitute_variables(content, &variables()).unwrap(),
+            content.replacen("{{ product }}", "Papyrus", 1)
+        );
+    }
+
+    #[test]
+    fn raw_blocks_are_substituted() {
+        assert_eq!(
+            substitute_variables("```{=html}\n<b>{{ product }}</b>\n```\n", &variables()).unwrap(),
+            "```{=html}\n<b>Papyrus</b>\n```\n"
+        );
+    }
+
+    #[test]
+    fn keeps_blocks_for_the_current_format_only() {
+        let content = "Intro\n::: {only=pdf}\nPrint\n:::\n::: {only=html,epub}\nScreen\n:::\nEnd\n";
+        assert_eq!(filter_conditional_blocks(content, OutputFormat::Pdf, 1).unwrap(), "Intro\nPrint\nEnd\n");
+        assert_eq!(filter_conditional_blocks(content, OutputFormat::Epub, 1).unwrap(), "Intro\nScreen\nEnd\n");
+    }
+
+    #[test]
+    fn nested_blocks_need_every_condition() {
+        let content = "::: {only=html,pdf}\nBoth\n:::: {only=pdf}\nPdf\n::::\n::: note\nNote\n:::\n:::\n";
+        assert_eq!(
+            filter_conditional_blocks(content, OutputFormat::Pdf, 1).unwrap(),
+            "Both\nPdf\n::: note\nNote\n:::\n"
+        );
+        assert_eq!(
+            filter_conditional_blocks(content, OutputFormat::Html, 1).unwrap(),
+            "Both\n::: note\nNote\n:::\n"
+        );
+        assert_eq!(filter_conditional_blocks(content, OutputFormat::Epub, 1).unwrap(), "");
+    }
+
+    #[test]
+    fn unclosed_blocks_are_errors() {
+        let error = filter_conditional_blocks("::: {only=pdf}\nPrint\n", OutputFormat::Html, 1).unwrap_err();
+        assert!(error.contains("unclosed"), "{}", error);
+
+        let nested = "::: {only=pdf}\n::: {only=pdf}\nPrint\n:::\n";
+        assert!(filter_conditional_blocks(nested, OutputFormat::Pdf, 1).is_err());
+    }
+
+    #[test]
+    fn unknown_formats_are_errors() {
+        let error = filter_conditional_blocks("Text\n::: {only=mobi}\nx\n:::\n", OutputFormat::Html, 1).unwrap_err();
+        assert!(error.starts_with("line 2:"), "{}", error);
+    }
+
+    #[test]
+    fn errors_count_lines_from_the_top_of_the_file() {
+        let path = std::env::temp_dir().join(format!("papyrus-conditional-{}.md", std::process::id()));
+        fs::write(&path, "---\ntitle: Lines\n---\n\n# Lines\n\nText\n::: {only=mobi}\nx\n:::\n").unwrap();
+        let error = MarkdownFile::load(&path, &Context::new(), OutputFormat::Html).unwrap_err().to_string();
+        fs::remove_file(&path).unwrap();
+        assert!(error.contains(": line 8: unknown output format `mobi`"), "{}", error);
+
+        let error = filter_conditional_blocks("Text\n\n::: {only=pdf}\nPrint\n", OutputFormat::Html, 5).unwrap_err();
+        assert!(error.starts_with("line 7: unclosed"), "{}", error);
+    }
+
+    #[test]
+    fn raw_block_errors_count_lines_from_the_top_of_the_file() {
+        let path = std::env::temp_dir().join(format!("papyrus-raw-{}.md", std::process::id()));
+        fs::write(&path, "---\ntitle: Lines\n---\n\n# Lines\n\n```{=epub}\n<p>Fine</p>\n<p>Broken</b>\n```\n").unwrap();
+        let error = MarkdownFile::load(&path, &Context::new(), OutputFormat::Epub).unwrap_err().to_string();
+        fs::remove_file(&path).unwrap();
+        assert!(error.contains(": line 9: raw epub block is not well-formed XHTML"), "{}", error);
+    }
+
+    #[test]
+    fn fences_in_code_blocks_are_left_alone() {
+        let content = "```markdown\n::: {only=pdf}\n```\nAfter\n";
+        assert_eq!(filter_conditional_blocks(content, OutputFormat::Html, 1).unwrap(), content);
+    }
+}
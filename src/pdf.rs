@@ -3,6 +3,8 @@ use crate::error::{PapyrusError, Result};
 use crate::config::Config;
 use crate::html;
 use crate::markdown;
+use crate::seo;
+use crate::theme;
 use std::fs;
 use tokio::process::Command;
 
@@ -15,7 +17,7 @@ pub async fn generate_pdf<P: AsRef<Path>>(book_dir: P, content_dir: P, theme: &s
     
     // Collect and parse markdown files
     let md_files = markdown::collect_markdown_files(content_dir, config.md_file_list.as_deref())?;
-    let parsed_files = markdown::parse_markdown_files(&md_files)?;
+    let parsed_files = markdown::parse_markdown_files(book_dir, &md_files, &config, markdown::OutputFormat::Pdf)?;
     
     // Process markdown with syntax highlighting
     let processed_files: Vec<markdown::MarkdownFile> = parsed_files
@@ -26,27 +28,45 @@ pub async fn generate_pdf<P: AsRef<Path>>(book_dir: P, content_dir: P, theme: &s
         })
         .collect();
     
-    // Load theme template
-    let theme_file = match theme {
-        "dark" => "theme-dark.html",
-        _ => "theme-light.html",
+    // Load theme template: the package's `pdf-<theme>.html` or `pdf.html`,
+    // otherwise the book's own light/dark theme
+    let (mut theme_package, scheme) = load_theme(book_dir, &config, theme)?;
+    let template_name = match format!("pdf-{}.html", scheme) {
+        name if theme_package.has_template(&name) => name,
+        _ => "pdf.html".to_string(),
     };
-    let theme_path = book_dir.join("assets").join(theme_file);
-    
-    if !theme_path.exists() {
-        return Err(PapyrusError::Asset(format!(
-            "Theme file not found: {}",
-            theme_path.display()
-        )));
-    }
+    theme_package.ensure_template(&template_name, || {
+        let theme_file = match scheme {
+            "dark" => "theme-dark.html",
+            _ => "theme-light.html",
+        };
+        let theme_path = book_dir.join("assets").join(theme_file);
+        
+        if !theme_path.exists() {
+            return Err(PapyrusError::Asset(format!(
+                "Theme file not found: {}",
+                theme_path.display()
+            )));
+        }
+        
+        Ok(fs::read_to_string(&theme_path)?)
+    })?;
+    
+    // Give headings ids so themes can link to them; chapters keep their
+    // `chapter-N` anchors
+    let anchors: Vec<String> = (1..=processed_files.len())
+        .map(|number| format!("chapter-{}", number))
+        .collect();
+    let processed_files = markdown::add_heading_ids(processed_files, &anchors, false);
+    let slugs = markdown::chapter_slugs(&processed_files);
     
-    let theme_content = fs::read_to_string(&theme_path)?;
+    let mut cover_url = None;
     
     // Generate cover page HTML if cover exists
     let cover_html = if let Some(cover_file) = &config.cover {
         let cover_path = book_dir.join("assets").join("images").join(cover_file);
         if cover_path.exists() {
-            let cover_url = cover_path.canonicalize()?
+            let cover_url_path = cover_path.canonicalize()?
                 .to_string_lossy()
                 .replace('\\', "/");
             
@@ -58,12 +78,13 @@ pub async fn generate_pdf<P: AsRef<Path>>(book_dir: P, content_dir: P, theme: &s
             
             match ext.as_str() {
                 "png" | "jpg" | "jpeg" | "gif" | "webp" | "svg" => {
+                    cover_url = Some(format!("file://{}", cover_url_path));
                     // For image covers, use img tag
                     format!(
                         r#"<div class="cover-page" style="page-break-after: always; text-align: center; padding: 2cm; min-height: 100vh; display: flex; align-items: center; justify-content: center; page: cover;">
                             <img src="file://{}" alt="Cover" style="max-width: 100%; max-height: 80vh; object-fit: contain;" />
                         </div>"#,
-                        cover_url
+                        cover_url_path
                     )
                 }
                 _ => {
@@ -105,23 +126,36 @@ pub async fn generate_pdf<P: AsRef<Path>>(book_dir: P, content_dir: P, theme: &s
         .join("\n");
     
     // Render template
-    use tera::{Tera, Context};
-    let mut tera = Tera::default();
-    tera.add_raw_template("theme", &theme_content)?;
-    
-    let mut context = Context::new();
-    context.insert("title", &config.title);
+    let chapters = theme::chapters(&processed_files, &slugs, |index, _| format!("#chapter-{}", index + 1));
+    let toc = theme::toc(&chapters, 3);
+    let mut context = theme::base_context(book_dir, &config, &chapters, &toc);
+    context.insert("cover", &cover_url);
+    context.insert("theme", scheme);
+    let static_url = match theme_package.static_dir() {
+        Some(dir) => format!("file://{}/", dir.canonicalize()?.to_string_lossy().replace('\\', "/")),
+        None => String::new(),
+    };
+    context.insert("static_url", &static_url);
     let full_content = format!("{}\n{}\n{}", cover_html, toc_html, combined_html);
     // For dark PDFs we use full-bleed rendering in some backends; keep consistent
     // margins by applying padding to an explicit wrapper element.
-    let full_content = if theme == "dark" {
+    let full_content = if scheme == "dark" {
         format!(r#"<div class="pdf-content">{}</div>"#, full_content)
     } else {
         full_content
     };
     context.insert("content", &full_content);
     
-    let rendered = tera.render("theme", &context)?;
+    let rendered = theme_package.render(&template_name, &context)?;
+    
+    // Colours for highlighted code
+    let highlight_scheme = if scheme == "dark" { "dark" } else { "light" };
+    let highlight_css = format!("<style>\n{}\n</style>\n</head>", html::highlight_css(highlight_scheme, ""));
+    let rendered = rendered.replacen("</head>", &highlight_css, 1);
+    
+    // Authors, subjects and publishing details for the document properties
+    let tags = seo::document_tags(&config, config.description()).join("\n");
+    let rendered = seo::insert_head_tags(&rendered, &tags);
     
     // Write temporary HTML file
     let export_dir = book_dir.join("export");
@@ -132,31 +166,49 @@ pub async fn generate_pdf<P: AsRef<Path>>(book_dir: P, content_dir: P, theme: &s
     
     // Generate PDF using external tool
     // Try wkhtmltopdf first, then weasyprint, then chrome/chromium
-    let filename_suffix = if theme == "dark" { "_dark" } else { "" };
+    let filename_suffix = match theme {
+        "light" => String::new(),
+        name => format!("_{}", name),
+    };
     let pdf_path = export_dir.join(format!("{}{}.pdf", sanitize_filename(&config.title), filename_suffix));
     
-    if let Ok(_) = generate_with_wkhtmltopdf(&temp_html, &pdf_path, theme).await {
-        fs::remove_file(&temp_html)?;
-        return Ok(());
-    }
+    // Render next to the previous PDF and swap it in once complete, so a
+    // failed build leaves the previous PDF intact
+    let temp_pdf = export_dir.join("temp_pdf.pdf");
+    let generated = generate_with_wkhtmltopdf(&temp_html, &temp_pdf, scheme).await.is_ok()
+        || generate_with_weasyprint(&temp_html, &temp_pdf, scheme).await.is_ok()
+        || generate_with_chrome(&temp_html, &temp_pdf, scheme).await.is_ok();
     
-    if let Ok(_) = generate_with_weasyprint(&temp_html, &pdf_path, theme).await {
-        fs::remove_file(&temp_html)?;
-        return Ok(());
-    }
+    fs::remove_file(&temp_html)?;
     
-    if let Ok(_) = generate_with_chrome(&temp_html, &pdf_path, theme).await {
-        fs::remove_file(&temp_html)?;
+    if generated {
+        fs::rename(&temp_pdf, &pdf_path)?;
         return Ok(());
     }
-    
-    fs::remove_file(&temp_html)?;
+    let _ = fs::remove_file(&temp_pdf);
     
     Err(PapyrusError::Pdf(
         "No PDF generator found. Please install one of: wkhtmltopdf, weasyprint, or Chrome/Chromium".to_string()
     ))
 }
 
+/// The theme for a PDF and its colour scheme. `light` and `dark` are the
+/// book's own theme; any other name is a theme package in `assets/themes/` or a
+/// built-in theme, used instead of `[theme]`.
+fn load_theme<'a>(book_dir: &Path, config: &Config, name: &'a str) -> Result<(theme::Theme, &'a str)> {
+    match name {
+        "light" | "dark" => Ok((theme::Theme::load(book_dir, config)?, name)),
+        _ if book_dir.join("assets").join("themes").join(name).is_dir() || theme::BuiltinTheme::find(name).is_some() => {
+            Ok((theme::Theme::named(book_dir, name)?, "light"))
+        }
+        _ => Err(PapyrusError::Pdf(format!(
+            "Unknown theme: {} (use light, dark, a built-in theme ({}) or a theme package in assets/themes/)",
+            name,
+            theme::BUILTIN_THEMES.iter().map(|theme| theme.name).collect::<Vec<_>>().join(", ")
+        ))),
+    }
+}
+
 async fn generate_with_wkhtmltopdf(html_path: &Path, pdf_path: &Path, theme: &str) -> Result<()> {
     // Load footer HTML template
     const FOOTER_HTML: &str = include_str!("../templates/pdf_footer.html");
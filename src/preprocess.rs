@@ -0,0 +1,205 @@
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use crate::config::{Config, PreprocessorConfig};
+use crate::error::{PapyrusError, Result};
+use crate::markdown::{FrontMatter, MarkdownFile, OutputFormat};
+
+/// What a preprocessor receives on stdin.
+#[derive(Serialize)]
+struct PreprocessorInput<'a> {
+    format: &'a str,
+    root: &'a Path,
+    config: &'a Config,
+    chapters: Vec<Chapter>,
+}
+
+/// What a preprocessor must print on stdout. Any other fields (such as the
+/// echoed `config`) are ignored.
+#[derive(Deserialize)]
+struct PreprocessorOutput {
+    chapters: Vec<Chapter>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Chapter {
+    path: PathBuf,
+    frontmatter: Option<FrontMatter>,
+    content: String,
+}
+
+/// Pipes the loaded book through every preprocessor configured for `format`,
+/// in the order they appear in `papyrus.toml`.
+///
+/// Each command runs from the book directory through the platform shell. It
+/// gets the book as JSON on stdin and must write the (possibly modified) book
+/// back as JSON on stdout.
+pub fn run_preprocessors(book_dir: &Path, config: &Config, format: OutputFormat, files: Vec<MarkdownFile>) -> Result<Vec<MarkdownFile>> {
+    let preprocessors: Vec<&PreprocessorConfig> = config.preprocessors
+        .iter()
+        .flatten()
+        .filter(|p| p.formats.as_ref().is_none_or(|formats| formats.iter().any(|f| f == format.as_str())))
+        .collect();
+
+    if preprocessors.is_empty() {
+        return Ok(files);
+    }
+
+    let mut chapters: Vec<Chapter> = files
+        .into_iter()
+        .map(|file| Chapter {
+            path: file.path,
+            frontmatter: file.frontmatter,
+            content: file.content,
+        })
+        .collect();
+
+    for preprocessor in preprocessors {
+        let input = PreprocessorInput {
+            format: format.as_str(),
+            root: book_dir,
+            config,
+            chapters,
+        };
+        let input = serde_json::to_vec(&input)
+            .map_err(|e| PapyrusError::Preprocessor(format!("Failed to serialize book: {}", e)))?;
+
+        let output = run_command(book_dir, &preprocessor.command, input)?;
+        let output: PreprocessorOutput = serde_json::from_slice(&output)
+            .map_err(|e| PapyrusError::Preprocessor(format!(
+                "`{}` returned invalid JSON: {}",
+                preprocessor.command,
+                e
+            )))?;
+        chapters = output.chapters;
+    }
+
+    Ok(chapters
+        .into_iter()
+        .map(|chapter| MarkdownFile {
+            path: chapter.path,
+            frontmatter: chapter.frontmatter,
+            content: chapter.content,
+            html: String::new(),
+        })
+        .collect())
+}
+
+fn run_command(book_dir: &Path, command: &str, input: Vec<u8>) -> Result<Vec<u8>> {
+    let mut cmd = if cfg!(windows) {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C").arg(command);
+        cmd
+    } else {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(command);
+        cmd
+    };
+
+    let mut child = cmd
+        .current_dir(book_dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .map_err(|e| PapyrusError::Preprocessor(format!("Failed to run `{}`: {}", command, e)))?;
+
+    // Feed stdin from another thread so a chatty command can't deadlock us
+    let mut stdin = child.stdin.take().unwrap();
+    let writer = std::thread::spawn(move || stdin.write_all(&input));
+
+    let output = child.wait_with_output()?;
+    // A command that ignores its input may close stdin early; that's fine
+    let _ = writer.join();
+
+    if !output.status.success() {
+        return Err(PapyrusError::Preprocessor(format!(
+            "`{}` exited with {}",
+            command,
+            output.status
+        )));
+    }
+
+    Ok(output.stdout)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn chapter(name: &str, title: &str, content: &str) -> MarkdownFile {
+        MarkdownFile {
+            path: PathBuf::from("content").join(name),
+            frontmatter: Some(FrontMatter {
+                title: Some(title.to_string()),
+                author: None,
+                date: Some("2024-05-01".to_string()),
+                extra: serde_yaml::Value::Mapping(Default::default()),
+            }),
+            content: content.to_string(),
+            html: String::new(),
+        }
+    }
+
+    fn config(preprocessors: &[(&str, Option<&str>)]) -> Config {
+        Config {
+            preprocessors: Some(
+                preprocessors
+                    .iter()
+                    .map(|(command, format)| PreprocessorConfig {
+                        command: command.to_string(),
+                        formats: format.map(|format| vec![format.to_string()]),
+                    })
+                    .collect(),
+            ),
+            ..Config::default()
+        }
+    }
+
+    fn book() -> Vec<MarkdownFile> {
+        vec![
+            chapter("01-intro.md", "Intro", "# Hello\n\nUnicode: “quotes” — ünïcödé\n"),
+            chapter("02-next.md", "Next", "Hello again\n"),
+        ]
+    }
+
+    #[test]
+    fn chapters_survive_a_round_trip_through_json() {
+        let dir = std::env::temp_dir();
+        let chapters = run_preprocessors(&dir, &config(&[("cat", None)]), OutputFormat::Html, book()).unwrap();
+
+        assert_eq!(chapters.len(), 2);
+        for (chapter, original) in chapters.iter().zip(book()) {
+            assert_eq!(chapter.path, original.path);
+            assert_eq!(chapter.content, original.content);
+            let (frontmatter, expected) = (chapter.frontmatter.as_ref().unwrap(), original.frontmatter.unwrap());
+            assert_eq!(frontmatter.title, expected.title);
+            assert_eq!(frontmatter.date, expected.date);
+        }
+    }
+
+    #[test]
+    fn preprocessors_run_in_order_for_their_formats() {
+        let dir = std::env::temp_dir();
+        let config = config(&[
+            ("sed 's/Hello/Bonjour/g'", None),
+            ("sed 's/Bonjour/Hola/g'", Some("html")),
+            ("false", Some("pdf")),
+        ]);
+        let chapters = run_preprocessors(&dir, &config, OutputFormat::Html, book()).unwrap();
+        assert_eq!(chapters[1].content, "Hola again\n");
+
+        let chapters = run_preprocessors(&dir, &config, OutputFormat::Epub, book()).unwrap();
+        assert_eq!(chapters[1].content, "Bonjour again\n");
+
+        let error = run_preprocessors(&dir, &config, OutputFormat::Pdf, book()).unwrap_err();
+        assert!(error.to_string().contains("`false` exited with"), "{}", error);
+    }
+
+    #[test]
+    fn invalid_output_is_an_error() {
+        let error = run_preprocessors(&std::env::temp_dir(), &config(&[("echo nope", None)]), OutputFormat::Html, book()).unwrap_err();
+        assert!(error.to_string().contains("`echo nope` returned invalid JSON"), "{}", error);
+    }
+}
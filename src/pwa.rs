@@ -0,0 +1,118 @@
+use image::imageops::FilterType;
+use serde_json::json;
+use std::collections::BTreeSet;
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use crate::config::Config;
+use crate::error::{PapyrusError, Result};
+use crate::seo;
+
+const SERVICE_WORKER_JS: &str = include_str!("../templates/service-worker.js");
+
+/// Square icons made from the cover, in pixels.
+const ICON_SIZES: &[u32] = &[192, 512];
+
+/// Turns a finished multi-page export into an installable web app that works
+/// offline: a web app manifest, icons from the cover, and a service worker
+/// precaching the book.
+///
+/// `cover` is the exported cover image, and `pages` the HTML pages, which get
+/// the tags registering all of it. `files` lists everything else the build
+/// wrote, as paths relative to `export_dir`; older files still lying around
+/// in the export are left out of the cache.
+pub fn write(export_dir: &Path, config: &Config, cover: Option<&Path>, pages: &[String], mut files: BTreeSet<String>) -> Result<()> {
+    let icons = match cover {
+        Some(cover) => write_icons(export_dir, cover)?,
+        None => {
+            eprintln!("Warning: no cover image, the web app will have no icons");
+            Vec::new()
+        }
+    };
+
+    let mut manifest = json!({
+        "name": config.title,
+        "short_name": config.title,
+        "start_url": "./",
+        "scope": "./",
+        "display": "standalone",
+        "background_color": "#ffffff",
+        "theme_color": "#ffffff",
+        "icons": icons,
+    });
+    if let Some(description) = config.description() {
+        manifest["description"] = json!(description);
+    }
+    if let Some(language) = &config.language {
+        manifest["lang"] = json!(language);
+    }
+    fs::write(export_dir.join("manifest.webmanifest"), format!("{:#}\n", manifest))?;
+    files.insert("manifest.webmanifest".to_string());
+    files.extend(icons.iter().filter_map(|icon| icon["src"].as_str()).map(str::to_string));
+    files.extend(pages.iter().cloned());
+
+    let apple_icon = icons.iter().find_map(|icon| icon["src"].as_str()).map(|src| {
+        format!("\n<link rel=\"apple-touch-icon\" href=\"{}\">", src)
+    });
+    let tags = format!(
+        "<link rel=\"manifest\" href=\"manifest.webmanifest\">\n<meta name=\"theme-color\" content=\"#ffffff\">{}\n<script>if (\"serviceWorker\" in navigator) {{ navigator.serviceWorker.register(\"sw.js\"); }}</script>",
+        apple_icon.unwrap_or_default()
+    );
+    for page in pages {
+        let path = export_dir.join(page);
+        let html = fs::read_to_string(&path)?;
+        fs::write(&path, seo::insert_head_tags(&html, &tags))?;
+    }
+
+    write_service_worker(export_dir, &files)
+}
+
+/// Writes the cover as square PNG icons, or links an SVG cover as it is, and
+/// returns their manifest entries.
+fn write_icons(export_dir: &Path, cover: &Path) -> Result<Vec<serde_json::Value>> {
+    let relative = cover.strip_prefix(export_dir).unwrap_or(cover);
+    let relative = relative.to_string_lossy().replace('\\', "/");
+    if cover.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("svg")) {
+        return Ok(vec![json!({ "src": relative, "sizes": "any", "type": "image/svg+xml" })]);
+    }
+
+    let image = image::open(cover)
+        .map_err(|e| PapyrusError::Asset(format!("Failed to read cover {}: {}", cover.display(), e)))?;
+    let mut icons = Vec::new();
+    for &size in ICON_SIZES {
+        let name = format!("icon-{}.png", size);
+        image
+            .resize_to_fill(size, size, FilterType::Lanczos3)
+            .save_with_format(export_dir.join(&name), image::ImageFormat::Png)
+            .map_err(|e| PapyrusError::Asset(format!("Failed to write {}: {}", name, e)))?;
+        icons.push(json!({
+            "src": name,
+            "sizes": format!("{0}x{0}", size),
+            "type": "image/png",
+            "purpose": "any",
+        }));
+    }
+    Ok(icons)
+}
+
+/// Writes `sw.js` listing `files`. The cache is named after their contents,
+/// so readers pick up a new build but not an identical one.
+fn write_service_worker(export_dir: &Path, files: &BTreeSet<String>) -> Result<()> {
+    let mut hasher = DefaultHasher::new();
+    for file in files {
+        file.hash(&mut hasher);
+        fs::read(export_dir.join(file))?.hash(&mut hasher);
+    }
+
+    // Opening the folder itself serves index.html
+    let precache: Vec<&str> = std::iter::once("./").chain(files.iter().map(String::as_str)).collect();
+    let script = format!(
+        "var PRECACHE = {};\nvar BUILD = \"{:016x}\";\n\n{}",
+        serde_json::to_string_pretty(&precache).unwrap_or_default(),
+        hasher.finish(),
+        SERVICE_WORKER_JS
+    );
+    fs::write(export_dir.join("sw.js"), script)?;
+    Ok(())
+}
@@ -0,0 +1,297 @@
+use serde_json::json;
+use std::fs;
+use std::path::Path;
+use crate::config::Config;
+use crate::error::Result;
+use crate::markdown::{self, MarkdownFile};
+
+/// Longest description taken from a chapter's text.
+const EXCERPT_LENGTH: usize = 160;
+
+/// Search engine and social media metadata for the pages of an HTML book.
+///
+/// Canonical links, `og:url`, absolute image URLs and the sitemap need the
+/// book's public address, `[html] site_url`; without it only the tags that
+/// work with relative URLs are written.
+pub struct Seo<'a> {
+    config: &'a Config,
+    site_url: Option<String>,
+    /// Where the pages are below the site URL, e.g. `1.2.0/`
+    path: String,
+    cover: Option<String>,
+    description: Option<String>,
+}
+
+impl<'a> Seo<'a> {
+    /// `cover` is the cover URL as used by the pages.
+    pub fn new(config: &'a Config, cover: Option<&str>, files: &[MarkdownFile]) -> Self {
+        let site_url = config.html
+            .as_ref()
+            .and_then(|html| html.site_url.as_deref())
+            .map(|url| url.trim_end_matches('/').to_string())
+            .filter(|url| !url.is_empty());
+
+        // Social cards need an image on the site, not an inline data: URI
+        let cover = cover.filter(|cover| !cover.contains(':')).map(str::to_string);
+
+        let description = config.description()
+            .map(str::to_string)
+            .or_else(|| files.first().and_then(excerpt));
+
+        Seo { config, site_url, path: String::new(), cover, description }
+    }
+
+    /// Metadata for pages published in `dir` below the site URL, such as
+    /// one edition of a versioned book.
+    pub fn within(mut self, dir: &str) -> Self {
+        self.path = format!("{}/", dir);
+        self
+    }
+
+    /// Metadata for a copy that isn't published on the site, such as the
+    /// standalone file: no canonical link, `og:url` or other site URLs.
+    pub fn unpublished(mut self) -> Self {
+        self.site_url = None;
+        self
+    }
+
+    /// Tags for the book's main page, including `Book` structured data.
+    /// `page` is the page's path relative to the site root.
+    pub fn book_tags(&self, page: &str) -> String {
+        let url = self.url(page);
+        let cover = self.cover_url();
+        let mut tags = self.common_tags(&self.config.title, self.description.as_deref(), url.as_deref(), "book");
+
+        let person = |name: &str| json!({ "@type": "Person", "name": name });
+        let authors: Vec<_> = self.config.authors().iter().map(|author| person(&author.name)).collect();
+        let mut book = json!({
+            "@context": "https://schema.org",
+            "@type": "Book",
+            "name": self.config.title,
+            "author": authors,
+        });
+        let metadata = self.config.metadata.clone().unwrap_or_default();
+        let isbn = self.config.isbn().map(|isbn| isbn.trim_start_matches("urn:isbn:").to_string());
+        let optional = [
+            ("description", self.description.as_deref()),
+            ("inLanguage", self.config.language.as_deref()),
+            ("bookEdition", self.config.version.as_deref()),
+            ("image", cover.as_deref()),
+            ("url", url.as_deref()),
+            ("isbn", isbn.as_deref()),
+            ("datePublished", metadata.date.as_deref()),
+            ("copyrightNotice", metadata.rights.as_deref()),
+        ];
+        for (key, value) in optional {
+            if let Some(value) = value {
+                book[key] = json!(value);
+            }
+        }
+        if let Some(publisher) = &metadata.publisher {
+            book["publisher"] = json!({ "@type": "Organization", "name": publisher });
+        }
+        if let Some(subjects) = metadata.subjects.as_ref().filter(|subjects| !subjects.is_empty()) {
+            book["keywords"] = json!(subjects.join(", "));
+        }
+        // Roles schema.org has a property for
+        for contributor in metadata.contributors.iter().flatten() {
+            let key = match contributor.role_code("ctb") {
+                Some("edt") => "editor",
+                Some("ill") => "illustrator",
+                Some("trl") => "translator",
+                _ => "contributor",
+            };
+            match book[key].as_array_mut() {
+                Some(people) => people.push(person(&contributor.name)),
+                None => book[key] = json!([person(&contributor.name)]),
+            }
+        }
+        if let Some(series) = &metadata.series {
+            book["isPartOf"] = json!({ "@type": "BookSeries", "name": series.name });
+            if let Some(position) = series.position {
+                book["position"] = json!(position);
+            }
+        }
+        tags.push(format!(
+            "<script type=\"application/ld+json\">{}</script>",
+            book.to_string().replace("</", "<\\/")
+        ));
+
+        tags.join("\n")
+    }
+
+    /// Tags for a chapter page. The description is the chapter's
+    /// `description` frontmatter, or the start of its text.
+    pub fn chapter_tags(&self, file: &MarkdownFile, title: &str, page: &str) -> String {
+        let description = file.frontmatter
+            .as_ref()
+            .and_then(|fm| fm.extra.get("description"))
+            .and_then(|description| description.as_str())
+            .map(str::to_string)
+            .or_else(|| excerpt(file));
+        let title = format!("{} - {}", title, self.config.title);
+        self.common_tags(&title, description.as_deref(), self.url(page).as_deref(), "article")
+            .join("\n")
+    }
+
+    /// Writes `sitemap.xml` listing `pages`, when the site URL is known.
+    pub fn write_sitemap(&self, export_dir: &Path, pages: &[String]) -> Result<()> {
+        if self.site_url.is_none() {
+            return Ok(());
+        }
+
+        let today = chrono::Local::now().format("%Y-%m-%d");
+        let mut sitemap = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n");
+        for page in pages {
+            sitemap.push_str(&format!(
+                "  <url>\n    <loc>{}</loc>\n    <lastmod>{}</lastmod>\n  </url>\n",
+                escape(&self.url(page).unwrap_or_default()),
+                today
+            ));
+        }
+        sitemap.push_str("</urlset>\n");
+        fs::write(export_dir.join("sitemap.xml"), sitemap)?;
+        Ok(())
+    }
+
+    /// Writes `robots.txt` at the site root in `dir`, pointing at `sitemaps`
+    /// (relative to the site URL), when the site URL is known.
+    pub fn write_robots(&self, dir: &Path, sitemaps: &[String]) -> Result<()> {
+        let site_url = match &self.site_url {
+            Some(site_url) => site_url,
+            None => return Ok(()),
+        };
+
+        let mut robots = String::from("User-agent: *\nAllow: /\n\n");
+        for sitemap in sitemaps {
+            robots.push_str(&format!("Sitemap: {}/{}\n", site_url, sitemap));
+        }
+        fs::write(dir.join("robots.txt"), robots)?;
+        Ok(())
+    }
+
+    fn common_tags(&self, title: &str, description: Option<&str>, url: Option<&str>, og_type: &str) -> Vec<String> {
+        let mut tags = Vec::new();
+        tags.extend(document_tags(self.config, description));
+        let mut meta = |attribute: &str, name: &str, content: &str| {
+            tags.push(format!("<meta {}=\"{}\" content=\"{}\">", attribute, name, escape(content)));
+        };
+
+        meta("property", "og:type", og_type);
+        meta("property", "og:title", title);
+        meta("property", "og:site_name", &self.config.title);
+        if let Some(description) = description {
+            meta("property", "og:description", description);
+        }
+        if let Some(url) = url {
+            meta("property", "og:url", url);
+        }
+        let cover = self.cover_url();
+        if let Some(cover) = &cover {
+            meta("property", "og:image", cover);
+        }
+
+        meta("name", "twitter:card", if cover.is_some() { "summary_large_image" } else { "summary" });
+        meta("name", "twitter:title", title);
+        if let Some(description) = description {
+            meta("name", "twitter:description", description);
+        }
+        if let Some(cover) = &cover {
+            meta("name", "twitter:image", cover);
+        }
+
+        if let Some(url) = url {
+            tags.push(format!("<link rel=\"canonical\" href=\"{}\">", escape(url)));
+        }
+        tags
+    }
+
+    fn cover_url(&self) -> Option<String> {
+        self.cover.as_ref().and_then(|cover| self.url(cover))
+    }
+
+    /// The absolute URL of a page, `index.html` being the site root.
+    fn url(&self, page: &str) -> Option<String> {
+        let page = if page == "index.html" { "" } else { page };
+        self.site_url.as_ref().map(|site_url| format!("{}/{}{}", site_url, self.path, page))
+    }
+}
+
+/// Document metadata from `papyrus.toml`: authors, description, subjects as
+/// keywords, and the `[metadata]` publishing details as Dublin Core terms.
+/// PDF generators use them for the document properties.
+pub fn document_tags(config: &Config, description: Option<&str>) -> Vec<String> {
+    let mut tags = Vec::new();
+    let mut meta = |name: &str, content: &str| {
+        tags.push(format!("<meta name=\"{}\" content=\"{}\">", name, escape(content)));
+    };
+
+    for author in config.authors() {
+        meta("author", &author.name);
+    }
+    if let Some(description) = description {
+        meta("description", description);
+    }
+    let metadata = match &config.metadata {
+        Some(metadata) => metadata,
+        None => return tags,
+    };
+    if let Some(subjects) = metadata.subjects.as_ref().filter(|subjects| !subjects.is_empty()) {
+        meta("keywords", &subjects.join(", "));
+    }
+    for contributor in metadata.contributors.iter().flatten() {
+        meta("dcterms.contributor", &contributor.name);
+    }
+    let optional = [
+        ("dcterms.publisher", metadata.publisher.clone()),
+        ("dcterms.rights", metadata.rights.clone()),
+        ("dcterms.created", metadata.date.clone()),
+        ("dcterms.identifier", config.isbn()),
+        ("dcterms.isPartOf", metadata.series.as_ref().map(|series| series.name.clone())),
+    ];
+    for (name, value) in optional {
+        if let Some(value) = value {
+            meta(name, &value);
+        }
+    }
+    tags
+}
+
+/// Adds `tags` to the end of a page's `<head>`.
+pub fn insert_head_tags(page: &str, tags: &str) -> String {
+    match page.find("</head>") {
+        Some(index) => format!("{}{}\n{}", &page[..index], tags, &page[index..]),
+        None => page.to_string(),
+    }
+}
+
+/// The start of a chapter's paragraphs, cut at a word boundary.
+fn excerpt(file: &MarkdownFile) -> Option<String> {
+    let paragraph_re = regex::Regex::new(r"(?s)<p>(.*?)</p>").unwrap();
+    let text = paragraph_re
+        .captures_iter(&file.html)
+        .map(|paragraph| markdown::html_to_text(&paragraph[1]))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let text = text.trim();
+    if text.is_empty() {
+        return None;
+    }
+    if text.chars().count() <= EXCERPT_LENGTH {
+        return Some(text.to_string());
+    }
+
+    let cut: String = text.chars().take(EXCERPT_LENGTH).collect();
+    let cut = match cut.rfind(' ') {
+        Some(index) => &cut[..index],
+        None => &cut,
+    };
+    Some(format!("{}…", cut.trim_end_matches([',', ';', ':', '.'])))
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
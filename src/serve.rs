@@ -0,0 +1,189 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+use crate::assets;
+use crate::error::Result;
+use crate::html::{self, BuildCache};
+use crate::watch::{self, BookWatcher};
+
+/// Where pages subscribe to rebuild notifications.
+const EVENTS_PATH: &str = "/__papyrus/events";
+
+/// Added to every HTML page served, so it reloads after each rebuild.
+const LIVE_RELOAD_SCRIPT: &str = r#"<script>
+(function () {
+    var events = new EventSource("/__papyrus/events");
+    events.onmessage = function () { location.reload(); };
+})();
+</script>
+"#;
+
+/// The page `/` serves, and the directory everything else is served from.
+#[derive(Clone)]
+struct Site {
+    root: PathBuf,
+    index: String,
+}
+
+/// Builds the HTML book, serves it on `localhost:<port>` and rebuilds it
+/// whenever the chapters, assets or configuration change. Open pages reload
+/// through server-sent events.
+pub async fn serve(book_dir: &Path, content_dir: &Path, multi_page: bool, port: u16) -> Result<()> {
+    let site = Arc::new(RwLock::new(None));
+    let mut cache = BuildCache::default();
+    if let Some(built) = build(book_dir, content_dir, multi_page, &mut cache).await {
+        *site.write().unwrap() = Some(built);
+    }
+
+    let listener = TcpListener::bind(("127.0.0.1", port)).await?;
+    println!("Serving the book at http://localhost:{}/ (press Ctrl+C to stop)", port);
+
+    let (reload, _) = broadcast::channel(16);
+    let mut watcher = BookWatcher::new(book_dir, content_dir)?;
+
+    {
+        let site = Arc::clone(&site);
+        let reload = reload.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = match listener.accept().await {
+                    Ok(connection) => connection,
+                    Err(_) => continue,
+                };
+                let current = site.read().unwrap().clone();
+                let events = reload.subscribe();
+                tokio::spawn(async move {
+                    let _ = handle_connection(stream, current, events).await;
+                });
+            }
+        });
+    }
+
+    loop {
+        let changed = watcher.next_change(watch::DEBOUNCE).await?;
+        for path in &changed {
+            println!("Changed: {}", path.display());
+        }
+        cache.changed(&changed);
+
+        if let Some(built) = build(book_dir, content_dir, multi_page, &mut cache).await {
+            *site.write().unwrap() = Some(built);
+            // Nobody listening is fine
+            let _ = reload.send(());
+        }
+    }
+}
+
+/// Rebuilds the book, reporting errors instead of stopping the server so the
+/// last good build stays up. Only changed chapters are rendered again, and
+/// assets only copied again when something other than a chapter changed.
+async fn build(book_dir: &Path, content_dir: &Path, multi_page: bool, cache: &mut BuildCache) -> Option<Site> {
+    let started = Instant::now();
+    match html::generate_html_cached(book_dir, content_dir, multi_page, false, cache).await {
+        Ok(main_page) => {
+            let (rendered, chapters) = cache.rendered();
+            println!("Built in {} ms ({} of {} chapters rendered)", started.elapsed().as_millis(), rendered, chapters);
+            Some(Site {
+                root: main_page.parent().unwrap_or(book_dir).to_path_buf(),
+                index: main_page.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+            })
+        }
+        Err(e) => {
+            eprintln!("Build failed: {}", e);
+            None
+        }
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream, site: Option<Site>, mut events: broadcast::Receiver<()>) -> Result<()> {
+    // Only the request line matters; bodies are never expected
+    let mut buffer = vec![0; 8192];
+    let mut length = 0;
+    while !buffer[..length].windows(4).any(|w| w == b"\r\n\r\n") && length < buffer.len() {
+        let read = stream.read(&mut buffer[length..]).await?;
+        if read == 0 {
+            return Ok(());
+        }
+        length += read;
+    }
+
+    let request = String::from_utf8_lossy(&buffer[..length]);
+    let mut parts = request.lines().next().unwrap_or_default().split_whitespace();
+    let (method, target) = (parts.next().unwrap_or_default(), parts.next().unwrap_or("/"));
+    if method != "GET" && method != "HEAD" {
+        return respond(&mut stream, "405 Method Not Allowed", "text/plain", b"Method not allowed").await;
+    }
+
+    let path = target.split(['?', '#']).next().unwrap_or("/");
+    if path == EVENTS_PATH {
+        stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\r\n").await?;
+        // Ends when the browser goes away and the write fails
+        while events.recv().await.is_ok() {
+            stream.write_all(b"data: reload\n\n").await?;
+        }
+        return Ok(());
+    }
+
+    let site = match site {
+        Some(site) => site,
+        None => return respond(&mut stream, "503 Service Unavailable", "text/plain", b"The book failed to build; see the terminal").await,
+    };
+    let file = match resolve(&site, path) {
+        Some(file) => file,
+        None => return respond(&mut stream, "404 Not Found", "text/plain", b"Not found").await,
+    };
+
+    let mut body = tokio::fs::read(&file).await?;
+    let is_html = file.extension().is_some_and(|ext| ext == "html");
+    if is_html {
+        let page = String::from_utf8_lossy(&body);
+        let page = match page.rfind("</body>") {
+            Some(index) => format!("{}{}{}", &page[..index], LIVE_RELOAD_SCRIPT, &page[index..]),
+            None => format!("{}{}", page, LIVE_RELOAD_SCRIPT),
+        };
+        body = page.into_bytes();
+    }
+    let content_type = if is_html {
+        "text/html; charset=utf-8"
+    } else {
+        assets::media_type(&file).unwrap_or("application/octet-stream")
+    };
+
+    if method == "HEAD" {
+        let head = format!("HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", content_type, body.len());
+        stream.write_all(head.as_bytes()).await?;
+        return Ok(());
+    }
+    respond(&mut stream, "200 OK", content_type, &body).await
+}
+
+/// Maps a URL path to a file of the site, refusing anything outside it.
+fn resolve(site: &Site, url_path: &str) -> Option<PathBuf> {
+    let decoded = assets::percent_decode(url_path.trim_start_matches('/'));
+    if Path::new(&decoded).is_absolute() || decoded.split(['/', '\\']).any(|segment| segment == "..") {
+        return None;
+    }
+
+    let mut file = site.root.join(&decoded);
+    if decoded.is_empty() {
+        file = site.root.join(&site.index);
+    } else if file.is_dir() {
+        file = file.join("index.html");
+    }
+    file.is_file().then_some(file)
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, content_type: &str, body: &[u8]) -> Result<()> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    Ok(())
+}
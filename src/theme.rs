@@ -0,0 +1,341 @@
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tera::{Context, Tera};
+use crate::config::{Config, ThemeConfig};
+use crate::error::{PapyrusError, Result};
+use crate::markdown::{self, FrontMatter, Heading, MarkdownFile};
+
+/// The templates a book is rendered with.
+///
+/// A theme package is a directory selected with `[theme]` in `papyrus.toml`:
+///
+/// ```text
+/// <theme>/
+///   templates/   pdf.html, pdf-dark.html, html.html, page.html, index.html,
+///                and any layouts or partials they extend or include
+///                (`partials/versions.html`, `partials/search.html` and
+///                `partials/search-scripts.html` are built in)
+///   static/      stylesheets, images and fonts, linked via `{{ static_url }}`
+/// ```
+///
+/// Every template is optional; whatever the package doesn't provide comes
+/// from the book's own `assets/theme-*.html` files. A name without a package
+/// directory selects one of the [`BUILTIN_THEMES`].
+pub struct Theme {
+    tera: Tera,
+    dir: Option<PathBuf>,
+}
+
+impl Theme {
+    pub fn load(book_dir: &Path, config: &Config) -> Result<Self> {
+        match &config.theme {
+            Some(theme_config) => Self::from_config(book_dir, theme_config),
+            None => Ok(Theme { tera: with_partials(unescaped(Tera::default()))?, dir: None }),
+        }
+    }
+
+    /// The theme package or built-in theme called `name`, whatever `[theme]`
+    /// says.
+    pub fn named(book_dir: &Path, name: &str) -> Result<Self> {
+        Self::from_config(book_dir, &ThemeConfig { name: name.to_string(), path: None })
+    }
+
+    fn from_config(book_dir: &Path, theme_config: &ThemeConfig) -> Result<Self> {
+        let dir = theme_config.directory(book_dir);
+        if !dir.is_dir() {
+            if let (None, Some(builtin)) = (&theme_config.path, BuiltinTheme::find(&theme_config.name)) {
+                let mut tera = unescaped(Tera::default());
+                tera.add_raw_templates(builtin.templates())?;
+                return Ok(Theme { tera: with_partials(tera)?, dir: None });
+            }
+            return Err(PapyrusError::Asset(format!(
+                "Theme not found: {} (built-in themes: {})",
+                dir.display(),
+                BUILTIN_THEMES.iter().map(|theme| theme.name).collect::<Vec<_>>().join(", ")
+            )));
+        }
+
+        let templates_dir = dir.join("templates");
+        let tera = if templates_dir.is_dir() {
+            let glob = templates_dir.join("**").join("*");
+            Tera::new(&glob.to_string_lossy().replace('\\', "/"))?
+        } else {
+            Tera::default()
+        };
+
+        Ok(Theme { tera: with_partials(unescaped(tera))?, dir: Some(dir) })
+    }
+
+    /// The theme package directory, if the book uses one.
+    pub fn dir(&self) -> Option<&Path> {
+        self.dir.as_deref()
+    }
+
+    /// The package's `static/` directory, if it has one.
+    pub fn static_dir(&self) -> Option<PathBuf> {
+        self.dir.as_ref().map(|dir| dir.join("static")).filter(|dir| dir.is_dir())
+    }
+
+    pub fn has_template(&self, name: &str) -> bool {
+        self.tera.get_template_names().any(|n| n == name)
+    }
+
+    /// Makes `name` renderable: the package's own template when it has one,
+    /// otherwise the source `fallback` returns.
+    pub fn ensure_template(&mut self, name: &str, fallback: impl FnOnce() -> Result<String>) -> Result<()> {
+        if !self.has_template(name) {
+            self.tera.add_raw_template(name, &fallback()?)?;
+        }
+        Ok(())
+    }
+
+    pub fn render(&self, name: &str, context: &Context) -> Result<String> {
+        Ok(self.tera.render(name, context)?)
+    }
+}
+
+/// Theme templates print rendered HTML as-is, whatever their file extension.
+fn unescaped(mut tera: Tera) -> Tera {
+    tera.autoescape_on(Vec::new());
+    tera
+}
+
+/// Adds the built-in partials the theme doesn't override, so the book's own
+/// `assets/theme-html*.html` files can include them too.
+fn with_partials(mut tera: Tera) -> Result<Tera> {
+    let missing: Vec<(&str, &str)> = BUILTIN_PARTIALS
+        .iter()
+        .copied()
+        .filter(|(name, _)| !tera.get_template_names().any(|n| n == *name))
+        .collect();
+    tera.add_raw_templates(missing)?;
+    Ok(tera)
+}
+
+/// A theme package compiled into papyrus. All of them share the same layouts
+/// and differ in `theme.css`.
+pub struct BuiltinTheme {
+    pub name: &'static str,
+    pub description: &'static str,
+    stylesheet: &'static str,
+}
+
+const BUILTIN_LAYOUTS: &[(&str, &str)] = &[
+    ("pdf.html", include_str!("../templates/themes/layout/pdf.html")),
+    ("html.html", include_str!("../templates/themes/layout/html.html")),
+    ("page.html", include_str!("../templates/themes/layout/page.html")),
+    ("index.html", include_str!("../templates/themes/layout/index.html")),
+    ("epub.css", include_str!("../templates/themes/layout/epub.css")),
+    ("search.css", include_str!("../templates/themes/layout/search.css")),
+];
+
+/// The version switcher and search box, shared by the HTML layouts.
+const BUILTIN_PARTIALS: &[(&str, &str)] = &[
+    ("partials/versions.html", include_str!("../templates/themes/layout/partials/versions.html")),
+    ("partials/search.html", include_str!("../templates/themes/layout/partials/search.html")),
+    ("partials/search-scripts.html", include_str!("../templates/themes/layout/partials/search-scripts.html")),
+];
+
+pub const BUILTIN_THEMES: &[BuiltinTheme] = &[
+    BuiltinTheme {
+        name: "classic",
+        description: "Traditional serif book with justified text and small caps",
+        stylesheet: include_str!("../templates/themes/classic/theme.css"),
+    },
+    BuiltinTheme {
+        name: "modern",
+        description: "Clean sans-serif for reading on screen",
+        stylesheet: include_str!("../templates/themes/modern/theme.css"),
+    },
+    BuiltinTheme {
+        name: "manual",
+        description: "Technical manual with numbered sections and dense tables",
+        stylesheet: include_str!("../templates/themes/manual/theme.css"),
+    },
+    BuiltinTheme {
+        name: "novel",
+        description: "Long-form fiction with indented paragraphs and drop caps",
+        stylesheet: include_str!("../templates/themes/novel/theme.css"),
+    },
+];
+
+impl BuiltinTheme {
+    pub fn find(name: &str) -> Option<&'static BuiltinTheme> {
+        BUILTIN_THEMES.iter().find(|theme| theme.name == name)
+    }
+
+    /// The theme's templates, by name.
+    pub fn templates(&self) -> Vec<(&'static str, &'static str)> {
+        BUILTIN_LAYOUTS
+            .iter()
+            .chain(BUILTIN_PARTIALS)
+            .copied()
+            .chain([("theme.css", self.stylesheet)])
+            .collect()
+    }
+
+    /// Writes the theme as a package into `dir`, which must not exist yet.
+    pub fn eject(&self, dir: &Path) -> Result<()> {
+        if dir.exists() {
+            return Err(PapyrusError::Asset(format!(
+                "Theme directory already exists: {}",
+                dir.display()
+            )));
+        }
+
+        let templates_dir = dir.join("templates");
+        std::fs::create_dir_all(&templates_dir)?;
+        for (name, source) in self.templates() {
+            let path = templates_dir.join(name);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(path, source)?;
+        }
+        Ok(())
+    }
+}
+
+/// A chapter as seen by theme templates.
+///
+/// Theme templates are rendered without autoescaping, so titles are
+/// HTML-escaped here while `html` is the rendered chapter body.
+#[derive(Debug, Clone, Serialize)]
+pub struct Chapter<'a> {
+    /// 1-based position in the book
+    pub number: usize,
+    pub title: String,
+    pub slug: &'a str,
+    pub url: String,
+    pub html: &'a str,
+    pub frontmatter: Option<&'a FrontMatter>,
+    pub headings: Vec<Heading>,
+}
+
+/// An entry of the table of contents: a chapter, or a heading nested under
+/// its chapter or a higher-level heading.
+#[derive(Debug, Clone, Serialize)]
+pub struct TocEntry {
+    pub title: String,
+    pub url: String,
+    /// 0 for chapters, the heading level otherwise
+    pub level: u8,
+    pub current: bool,
+    pub children: Vec<TocEntry>,
+}
+
+/// Facts about the build itself, for colophons and footers.
+#[derive(Debug, Clone, Serialize)]
+pub struct BuildInfo {
+    /// `YYYY-MM-DD`
+    pub date: String,
+    /// RFC 3339
+    pub timestamp: String,
+    pub papyrus_version: &'static str,
+    /// Short commit hash when the book lives in a git repository
+    pub git_commit: Option<String>,
+}
+
+impl BuildInfo {
+    pub fn collect(book_dir: &Path) -> Self {
+        let now = chrono::Local::now();
+        let git_commit = Command::new("git")
+            .args(["rev-parse", "--short", "HEAD"])
+            .current_dir(book_dir)
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+            .filter(|commit| !commit.is_empty());
+
+        BuildInfo {
+            date: now.format("%Y-%m-%d").to_string(),
+            timestamp: now.to_rfc3339(),
+            papyrus_version: env!("CARGO_PKG_VERSION"),
+            git_commit,
+        }
+    }
+}
+
+/// Describes every chapter for the templates. `chapter_url` maps a chapter's
+/// index and slug to where it lives in the output.
+pub fn chapters<'a>(files: &'a [MarkdownFile], slugs: &'a [String], chapter_url: impl Fn(usize, &str) -> String) -> Vec<Chapter<'a>> {
+    files
+        .iter()
+        .zip(slugs)
+        .enumerate()
+        .map(|(index, (file, slug))| Chapter {
+            number: index + 1,
+            title: html_escape(&markdown::chapter_title(file, index)),
+            slug,
+            url: chapter_url(index, slug),
+            html: &file.html,
+            frontmatter: file.frontmatter.as_ref(),
+            headings: markdown::extract_headings(&file.html)
+                .into_iter()
+                .map(|heading| Heading { title: html_escape(&heading.title), ..heading })
+                .collect(),
+        })
+        .collect()
+}
+
+/// Builds the table of contents: every chapter, with its headings down to
+/// `max_level` nested underneath.
+pub fn toc(chapters: &[Chapter], max_level: u8) -> Vec<TocEntry> {
+    chapters
+        .iter()
+        .map(|chapter| {
+            let mut children = Vec::new();
+            for heading in chapter.headings.iter().filter(|h| h.level <= max_level) {
+                // On a single page the chapter URL is an anchor itself
+                let url = match chapter.url.split_once('#') {
+                    Some((page, _)) => format!("{}#{}", page, heading.id),
+                    None => format!("{}#{}", chapter.url, heading.id),
+                };
+                insert_nested(&mut children, TocEntry {
+                    title: heading.title.clone(),
+                    url,
+                    level: heading.level,
+                    current: false,
+                    children: Vec::new(),
+                });
+            }
+
+            TocEntry {
+                title: chapter.title.clone(),
+                url: chapter.url.clone(),
+                level: 0,
+                current: false,
+                children,
+            }
+        })
+        .collect()
+}
+
+fn insert_nested(entries: &mut Vec<TocEntry>, entry: TocEntry) {
+    match entries.last_mut() {
+        Some(last) if last.level < entry.level => insert_nested(&mut last.children, entry),
+        _ => entries.push(entry),
+    }
+}
+
+/// The variables every theme template gets: `title`, `book` (the whole
+/// configuration), `chapters`, `toc` and `build`.
+pub fn base_context(book_dir: &Path, config: &Config, chapters: &[Chapter], toc: &[TocEntry]) -> Context {
+    let mut context = Context::new();
+    context.insert("title", &config.title);
+    context.insert("book", config);
+    context.insert("chapters", chapters);
+    context.insert("toc", toc);
+    context.insert("build", &BuildInfo::collect(book_dir));
+    context
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace("&", "&amp;")
+        .replace("<", "&lt;")
+        .replace(">", "&gt;")
+        .replace("\"", "&quot;")
+        .replace("'", "&#x27;")
+}
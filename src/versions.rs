@@ -0,0 +1,185 @@
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::fs;
+use std::path::Path;
+use crate::error::{PapyrusError, Result};
+
+/// The `versions.json` manifest next to the editions of a versioned HTML
+/// book, listing them newest first.
+#[derive(Serialize, Deserialize, Default)]
+pub struct Versions {
+    pub latest: Option<String>,
+    pub versions: Vec<Version>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Version {
+    pub version: String,
+    /// The edition's folder, relative to the manifest
+    pub url: String,
+}
+
+/// An entry of the version switcher, as seen from a page of one edition.
+#[derive(Serialize)]
+pub struct VersionLink {
+    pub version: String,
+    pub url: String,
+    pub current: bool,
+    pub latest: bool,
+}
+
+impl Versions {
+    /// Reads `versions.json` from `root`, or starts an empty manifest.
+    pub fn load(root: &Path) -> Result<Self> {
+        let path = root.join("versions.json");
+        if !path.exists() {
+            return Ok(Versions::default());
+        }
+        serde_json::from_str(&fs::read_to_string(&path)?)
+            .map_err(|e| PapyrusError::Config(format!("Invalid {}: {}", path.display(), e)))
+    }
+
+    /// Adds `version` if it isn't listed yet, keeping the list sorted.
+    pub fn add(&mut self, version: &str) {
+        if !self.versions.iter().any(|v| v.version == version) {
+            self.versions.push(Version {
+                version: version.to_string(),
+                url: format!("{}/", version),
+            });
+        }
+        self.versions.sort_by(|a, b| compare_versions(&b.version, &a.version));
+        self.latest = self.versions.first().map(|v| v.version.clone());
+    }
+
+    /// The switcher entries for the pages of `current`.
+    pub fn links(&self, current: &str) -> Vec<VersionLink> {
+        self.versions
+            .iter()
+            .map(|v| VersionLink {
+                version: v.version.clone(),
+                url: format!("../{}", v.url),
+                current: v.version == current,
+                latest: self.latest.as_ref() == Some(&v.version),
+            })
+            .collect()
+    }
+
+    /// Writes `versions.json`, and an `index.html` sending visitors to the
+    /// latest edition.
+    pub fn save(&self, root: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)
+            .map_err(|e| PapyrusError::Config(format!("Failed to write versions.json: {}", e)))?;
+        fs::write(root.join("versions.json"), json + "\n")?;
+
+        if let Some(latest) = self.versions.first() {
+            fs::write(
+                root.join("index.html"),
+                format!(
+                    "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"UTF-8\">\n<meta http-equiv=\"refresh\" content=\"0; url={0}\">\n<link rel=\"canonical\" href=\"{0}\">\n<title>Redirecting</title>\n</head>\n<body>\n<p><a href=\"{0}\">Go to the latest version ({1})</a></p>\n</body>\n</html>\n",
+                    latest.url, latest.version
+                ),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks that a version can name the folder of its edition.
+pub fn validate(version: &str) -> Result<()> {
+    if version.is_empty() {
+        return Err(PapyrusError::Config("versioned builds need a `version` in papyrus.toml".to_string()));
+    }
+    let valid = !version.starts_with('.')
+        && version.chars().all(|c| c.is_alphanumeric() || matches!(c, '.' | '-' | '_' | '+'));
+    if valid {
+        Ok(())
+    } else {
+        Err(PapyrusError::Config(format!(
+            "version \"{}\" can't be used as a folder name for a versioned build",
+            version
+        )))
+    }
+}
+
+/// Orders versions like `1.2.0` < `1.10.0`, with pre-releases such as
+/// `2.0.0-beta` before the release itself.
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    fn split(version: &str) -> (&str, Option<&str>) {
+        let version = version.trim_start_matches('v');
+        match version.split_once('-') {
+            Some((release, pre)) => (release, Some(pre)),
+            None => (version, None),
+        }
+    }
+    fn compare_parts(a: &str, b: &str) -> Ordering {
+        let mut a_parts = a.split(['.', '+']);
+        let mut b_parts = b.split(['.', '+']);
+        loop {
+            match (a_parts.next(), b_parts.next()) {
+                (Some(x), Some(y)) => {
+                    let ordering = match (x.parse::<u64>(), y.parse::<u64>()) {
+                        (Ok(x), Ok(y)) => x.cmp(&y),
+                        _ => x.cmp(y),
+                    };
+                    if ordering != Ordering::Equal {
+                        return ordering;
+                    }
+                }
+                (Some(_), None) => return Ordering::Greater,
+                (None, Some(_)) => return Ordering::Less,
+                (None, None) => return Ordering::Equal,
+            }
+        }
+    }
+
+    let (a_release, a_pre) = split(a);
+    let (b_release, b_pre) = split(b);
+    compare_parts(a_release, b_release).then_with(|| match (a_pre, b_pre) {
+        (None, None) => Ordering::Equal,
+        (None, Some(_)) => Ordering::Greater,
+        (Some(_), None) => Ordering::Less,
+        (Some(x), Some(y)) => compare_parts(x, y),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_version_numbers_numerically() {
+        assert_eq!(compare_versions("1.10.0", "1.2.0"), Ordering::Greater);
+        assert_eq!(compare_versions("1.2.0", "1.10.0"), Ordering::Less);
+        assert_eq!(compare_versions("2.0", "2.0.1"), Ordering::Less);
+        assert_eq!(compare_versions("v1.2.0", "1.2.0"), Ordering::Equal);
+    }
+
+    #[test]
+    fn pre_releases_come_before_the_release() {
+        assert_eq!(compare_versions("2.0.0-beta", "2.0.0"), Ordering::Less);
+        assert_eq!(compare_versions("2.0.0-alpha", "2.0.0-beta"), Ordering::Less);
+        assert_eq!(compare_versions("2.0.0-beta.2", "2.0.0-beta.10"), Ordering::Less);
+        assert_eq!(compare_versions("2.0.0-rc.1", "1.10.0"), Ordering::Greater);
+    }
+
+    #[test]
+    fn lists_versions_newest_first() {
+        let mut versions = Versions::default();
+        for version in ["1.2.0", "1.10.0", "2.0.0-beta", "1.9.1", "2.0.0"] {
+            versions.add(version);
+        }
+        versions.add("1.10.0");
+
+        let listed: Vec<&str> = versions.versions.iter().map(|v| v.version.as_str()).collect();
+        assert_eq!(listed, ["2.0.0", "2.0.0-beta", "1.10.0", "1.9.1", "1.2.0"]);
+        assert_eq!(versions.latest.as_deref(), Some("2.0.0"));
+    }
+
+    #[test]
+    fn versions_must_be_folder_names() {
+        assert!(validate("1.2.0-rc.1+build.5").is_ok());
+        assert!(validate("").is_err());
+        assert!(validate("../1.0").is_err());
+        assert!(validate("1.0/latest").is_err());
+    }
+}
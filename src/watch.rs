@@ -0,0 +1,116 @@
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use crate::error::Result;
+
+/// How long the sources must stay quiet before a rebuild starts. Editors
+/// often write a file in several steps.
+pub const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Runs `build`, then runs it again whenever the book changes, until the
+/// process is interrupted. A failed build is reported and leaves the previous
+/// output in place.
+pub async fn rebuild_on_change<F, Fut>(book_dir: &Path, content_dir: &Path, label: &str, mut build: F) -> Result<()>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let mut watcher = BookWatcher::new(book_dir, content_dir)?;
+    run_build(label, &mut build).await;
+    println!("Watching for changes (press Ctrl+C to stop)");
+
+    loop {
+        let changed = watcher.next_change(DEBOUNCE).await?;
+        let names: Vec<String> = changed.iter().map(|path| path.display().to_string()).collect();
+        println!("\nRebuilding after changes to: {}", names.join(", "));
+        run_build(label, &mut build).await;
+    }
+}
+
+async fn run_build<F, Fut>(label: &str, build: &mut F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let started = Instant::now();
+    match build().await {
+        Ok(()) => println!("{} generated in {:.2}s", label, started.elapsed().as_secs_f64()),
+        Err(e) => eprintln!("{} build failed, keeping the previous output: {}", label, e),
+    }
+}
+
+/// Watches everything a build reads: the chapters, `assets/` (themes,
+/// styles, images) and `papyrus.toml`.
+pub struct BookWatcher {
+    // Dropping the watcher stops it
+    _watcher: RecommendedWatcher,
+    events: UnboundedReceiver<notify::Result<Event>>,
+    config_path: PathBuf,
+}
+
+impl BookWatcher {
+    pub fn new(book_dir: &Path, content_dir: &Path) -> Result<Self> {
+        let (sender, events) = mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event| {
+            let _ = sender.send(event);
+        })?;
+
+        watcher.watch(content_dir, RecursiveMode::Recursive)?;
+        let assets_dir = book_dir.join("assets");
+        if assets_dir.is_dir() {
+            watcher.watch(&assets_dir, RecursiveMode::Recursive)?;
+        }
+        // Editors often replace files instead of writing them, so watch the
+        // directory rather than `papyrus.toml` itself
+        watcher.watch(book_dir, RecursiveMode::NonRecursive)?;
+
+        let config_path = book_dir.join("papyrus.toml");
+        let config_path = config_path.canonicalize().unwrap_or(config_path);
+
+        Ok(BookWatcher { _watcher: watcher, events, config_path })
+    }
+
+    /// Waits for the next change and returns every file touched until the
+    /// sources have been quiet for `debounce`, relative to the book directory
+    /// where possible.
+    pub async fn next_change(&mut self, debounce: Duration) -> Result<Vec<PathBuf>> {
+        let mut changed = BTreeSet::new();
+
+        while changed.is_empty() {
+            let event = self.events.recv().await.expect("watcher channel closed")?;
+            self.collect(event, &mut changed);
+        }
+
+        while let Ok(Some(event)) = tokio::time::timeout(debounce, self.events.recv()).await {
+            self.collect(event?, &mut changed);
+        }
+
+        Ok(changed.into_iter().collect())
+    }
+
+    fn collect(&self, event: Event, changed: &mut BTreeSet<PathBuf>) {
+        if matches!(event.kind, EventKind::Access(_)) {
+            return;
+        }
+
+        for path in event.paths {
+            // Editor swap and backup files
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if name.starts_with('.') || name.ends_with('~') {
+                continue;
+            }
+            // The only file of interest directly inside the book directory
+            let in_book_root = path.parent().is_some_and(|dir| {
+                dir.canonicalize().ok() == self.config_path.parent().map(Path::to_path_buf)
+            });
+            if in_book_root && path.file_name() != self.config_path.file_name() {
+                continue;
+            }
+            let relative = self.config_path.parent().and_then(|root| path.strip_prefix(root).ok());
+            changed.insert(relative.map(Path::to_path_buf).unwrap_or(path));
+        }
+    }
+}
@@ -0,0 +1,165 @@
+// Reader preferences for papyrus HTML books: colour scheme, font size and
+// line width, remembered in localStorage. Include it in <head> so the saved
+// colour scheme applies before the page is drawn.
+(function () {
+    var root = document.documentElement;
+    var themes = ["light", "dark", "sepia"];
+    var widths = { narrow: "36em", medium: "48em", wide: "64em" };
+    var minScale = 70, maxScale = 160, step = 10;
+
+    function load(key) {
+        try { return localStorage.getItem("papyrus-" + key); } catch (e) { return null; }
+    }
+
+    function save(key, value) {
+        try { localStorage.setItem("papyrus-" + key, value); } catch (e) {}
+    }
+
+    var darkQuery = window.matchMedia ? window.matchMedia("(prefers-color-scheme: dark)") : null;
+
+    function applyTheme() {
+        var theme = load("theme");
+        if (themes.indexOf(theme) < 0) {
+            theme = darkQuery && darkQuery.matches ? "dark" : "light";
+        }
+        root.setAttribute("data-theme", theme);
+    }
+
+    function applyWidth() {
+        var width = load("width");
+        if (widths[width]) {
+            root.setAttribute("data-width", width);
+            root.style.setProperty("--reader-width", widths[width]);
+        } else {
+            root.removeAttribute("data-width");
+            root.style.removeProperty("--reader-width");
+        }
+    }
+
+    var baseFontSize = null;
+
+    function applyFontSize() {
+        var scale = parseInt(load("font-scale"), 10) || 100;
+        if (baseFontSize === null) {
+            baseFontSize = parseFloat(window.getComputedStyle(document.body).fontSize);
+        }
+        document.body.style.fontSize = scale === 100 ? "" : (baseFontSize * scale / 100) + "px";
+    }
+
+    applyTheme();
+    applyWidth();
+    if (darkQuery && darkQuery.addEventListener) {
+        // Follow the system until the reader picks a scheme
+        darkQuery.addEventListener("change", applyTheme);
+    }
+
+    function button(label, title, onClick) {
+        var element = document.createElement("button");
+        element.type = "button";
+        element.textContent = label;
+        element.title = title;
+        element.onclick = onClick;
+        return element;
+    }
+
+    function row(name, buttons) {
+        var element = document.createElement("div");
+        element.className = "reader-row";
+        var label = document.createElement("span");
+        label.textContent = name;
+        element.appendChild(label);
+        buttons.forEach(function (b) { element.appendChild(b); });
+        return element;
+    }
+
+    function buildControls() {
+        applyFontSize();
+
+        var panel = document.createElement("div");
+        panel.className = "reader-panel";
+        panel.hidden = true;
+
+        panel.appendChild(row("Theme", themes.map(function (theme) {
+            return button(theme.charAt(0).toUpperCase() + theme.slice(1), "Use the " + theme + " colour scheme", function () {
+                save("theme", theme);
+                applyTheme();
+            });
+        })));
+
+        function changeScale(delta) {
+            var scale = (parseInt(load("font-scale"), 10) || 100) + delta;
+            save("font-scale", Math.min(maxScale, Math.max(minScale, scale)));
+            applyFontSize();
+        }
+        panel.appendChild(row("Text", [
+            button("A−", "Smaller text", function () { changeScale(-step); }),
+            button("A+", "Larger text", function () { changeScale(step); })
+        ]));
+
+        panel.appendChild(row("Width", Object.keys(widths).map(function (width) {
+            return button(width.charAt(0).toUpperCase() + width.slice(1), "Use " + width + " lines", function () {
+                save("width", width);
+                applyWidth();
+            });
+        })));
+
+        // A chapter page prints just that chapter
+        panel.appendChild(button("Print", "Print this page", function () {
+            panel.hidden = true;
+            window.print();
+        }));
+
+        panel.appendChild(button("Reset", "Back to the defaults", function () {
+            ["theme", "font-scale", "width"].forEach(function (key) {
+                try { localStorage.removeItem("papyrus-" + key); } catch (e) {}
+            });
+            applyTheme();
+            applyWidth();
+            applyFontSize();
+        }));
+
+        var toggle = button("Aa", "Reading settings", function () {
+            panel.hidden = !panel.hidden;
+        });
+        toggle.className = "reader-toggle";
+
+        var container = document.createElement("div");
+        container.className = "reader-settings";
+        container.appendChild(toggle);
+        container.appendChild(panel);
+        document.body.appendChild(container);
+    }
+
+    // Editions of a versioned book list the versions known when they were
+    // built; when served over HTTP, newer ones come from versions.json
+    function refreshVersions() {
+        var select = document.querySelector("select.version-switcher[data-manifest]");
+        if (!select || !window.fetch || location.protocol === "file:") {
+            return;
+        }
+        fetch(select.getAttribute("data-manifest")).then(function (response) {
+            return response.json();
+        }).then(function (manifest) {
+            var current = select.value;
+            select.innerHTML = "";
+            manifest.versions.forEach(function (version) {
+                var option = document.createElement("option");
+                option.value = "../" + version.url;
+                option.textContent = version.version + (version.version === manifest.latest ? " (latest)" : "");
+                option.selected = option.value === current;
+                select.appendChild(option);
+            });
+        }).catch(function () {});
+    }
+
+    function ready() {
+        buildControls();
+        refreshVersions();
+    }
+
+    if (document.readyState === "loading") {
+        document.addEventListener("DOMContentLoaded", ready);
+    } else {
+        ready();
+    }
+})();
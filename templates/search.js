@@ -0,0 +1,89 @@
+// Offline full-text search for papyrus HTML books.
+// Expects `window.searchIndex` (loaded from searchindex.js) and the
+// `#search-input` / `#search-results` elements from the theme.
+(function () {
+    var input = document.getElementById('search-input');
+    var results = document.getElementById('search-results');
+    var index = window.searchIndex || [];
+    if (!input || !results) {
+        return;
+    }
+
+    function escapeHtml(s) {
+        return s.replace(/[&<>"']/g, function (c) {
+            return { '&': '&amp;', '<': '&lt;', '>': '&gt;', '"': '&quot;', "'": '&#39;' }[c];
+        });
+    }
+
+    function search(query) {
+        var terms = query.toLowerCase().split(/\s+/).filter(Boolean);
+        if (terms.length === 0) {
+            return [];
+        }
+
+        var matches = [];
+        index.forEach(function (entry) {
+            var heading = entry.heading.toLowerCase();
+            var text = entry.text.toLowerCase();
+            var score = 0;
+            for (var i = 0; i < terms.length; i++) {
+                if (heading.indexOf(terms[i]) !== -1) {
+                    score += 10;
+                } else if (text.indexOf(terms[i]) !== -1) {
+                    score += 1;
+                } else {
+                    return;
+                }
+            }
+            matches.push({ entry: entry, score: score, term: terms[0] });
+        });
+
+        matches.sort(function (a, b) { return b.score - a.score; });
+        return matches.slice(0, 20);
+    }
+
+    function snippet(text, term) {
+        var at = text.toLowerCase().indexOf(term);
+        var start = Math.max(0, at - 60);
+        var excerpt = (start > 0 ? '…' : '') + text.substr(start, 160) + (start + 160 < text.length ? '…' : '');
+        var escaped = escapeHtml(excerpt);
+        if (at === -1) {
+            return escaped;
+        }
+        var pattern = new RegExp('(' + escapeHtml(term).replace(/[.*+?^${}()|[\]\\]/g, '\\$&') + ')', 'ig');
+        return escaped.replace(pattern, '<mark>$1</mark>');
+    }
+
+    function render() {
+        var matches = search(input.value);
+        results.innerHTML = '';
+        results.hidden = input.value.trim() === '';
+        if (!results.hidden && matches.length === 0) {
+            results.innerHTML = '<li class="search-empty">No results</li>';
+            return;
+        }
+        matches.forEach(function (match) {
+            var entry = match.entry;
+            var title = entry.heading === entry.chapter
+                ? escapeHtml(entry.chapter)
+                : escapeHtml(entry.chapter) + ' &rsaquo; ' + escapeHtml(entry.heading);
+            var item = document.createElement('li');
+            item.innerHTML = '<a href="' + escapeHtml(entry.url) + '">' + title + '</a>'
+                + '<p>' + snippet(entry.text, match.term) + '</p>';
+            results.appendChild(item);
+        });
+    }
+
+    input.addEventListener('input', render);
+    input.addEventListener('keydown', function (event) {
+        if (event.key === 'Escape') {
+            input.value = '';
+            render();
+        }
+    });
+    results.addEventListener('click', function (event) {
+        if (event.target.closest('a')) {
+            results.hidden = true;
+        }
+    });
+})();
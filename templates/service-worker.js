@@ -0,0 +1,50 @@
+// Service worker for papyrus HTML books: keeps every page and file of the
+// book in the cache so it can be read offline. PRECACHE and BUILD are
+// written above by papyrus.
+var PREFIX = "papyrus:" + self.registration.scope + ":";
+var CACHE = PREFIX + BUILD;
+var PRECACHED = PRECACHE.map(function (path) {
+    return new URL(path, self.registration.scope).href;
+});
+
+self.addEventListener("install", function (event) {
+    event.waitUntil(caches.open(CACHE).then(function (cache) {
+        return cache.addAll(PRECACHED);
+    }).then(function () {
+        return self.skipWaiting();
+    }));
+});
+
+self.addEventListener("activate", function (event) {
+    // Drop the copies of earlier builds of this book
+    event.waitUntil(caches.keys().then(function (keys) {
+        return Promise.all(keys.filter(function (key) {
+            return key.indexOf(PREFIX) === 0 && key !== CACHE;
+        }).map(function (key) {
+            return caches.delete(key);
+        }));
+    }).then(function () {
+        return self.clients.claim();
+    }));
+});
+
+self.addEventListener("fetch", function (event) {
+    var request = event.request;
+    var url = request.url.split("#")[0].split("?")[0];
+    if (request.method !== "GET" || (PRECACHED.indexOf(url) < 0 && request.mode !== "navigate")) {
+        return;
+    }
+
+    // Online readers always get the latest build; the cache is the fallback
+    event.respondWith(fetch(request).then(function (response) {
+        if (response.ok && PRECACHED.indexOf(url) >= 0) {
+            var copy = response.clone();
+            caches.open(CACHE).then(function (cache) { cache.put(url, copy); });
+        }
+        return response;
+    }).catch(function () {
+        return caches.match(url).then(function (cached) {
+            return cached || caches.match(new URL("index.html", self.registration.scope).href);
+        });
+    }));
+});